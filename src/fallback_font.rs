@@ -0,0 +1,259 @@
+// 先頭のフォントにない文字を後ろのフォントから探して 1 つのフォントとして扱う
+// glyph id は前にあるフォントの glyph 数だけずらして重ならないようにする
+use ab_glyph::{point, Font, FontVec, GlyphId, Outline, OutlineCurve, Point};
+
+// R2 から読み込んだフォントをまとめて持ち、描くときは FallbackFont として並べて使う
+// 先頭の base 個が既定のフォントと代替フォントで、残りは部分ごとのフォント
+pub(crate) struct FontCollection {
+    fonts: Vec<FontVec>,
+    base: usize,
+}
+
+impl FontCollection {
+    pub(crate) fn new(fonts: Vec<FontVec>) -> Self {
+        let base = fonts.len();
+        FontCollection { fonts, base }
+    }
+
+    // 部分ごとのフォントを足して、fallback に渡す番号を返す
+    pub(crate) fn push(&mut self, font: FontVec) -> usize {
+        self.fonts.push(font);
+        self.fonts.len() - 1
+    }
+
+    // first のフォントを先頭にして、含まれない文字は既定のフォントから探す
+    pub(crate) fn fallback(&self, first: Option<usize>) -> FallbackFont<&FontVec> {
+        FallbackFont::new(
+            first
+                .map(|index| &self.fonts[index])
+                .into_iter()
+                .chain(&self.fonts[..self.base])
+                .collect(),
+        )
+    }
+}
+
+pub(crate) struct FallbackFont<F> {
+    fonts: Vec<Fallback<F>>,
+}
+
+struct Fallback<F> {
+    font: F,
+    // この font の glyph id に足す値
+    offset: u16,
+    // 先頭のフォントの単位に揃えるための倍率 (ab_glyph は height で大きさを決める)
+    scale: f32,
+}
+
+impl<F: Font> FallbackFont<F> {
+    // glyph id が u16 に収まらなくなるフォントは使わない
+    pub(crate) fn new(fonts: Vec<F>) -> Self {
+        let mut fallbacks: Vec<Fallback<F>> = Vec::new();
+        let mut offset = 0usize;
+        for font in fonts {
+            if offset + font.glyph_count() > u16::MAX as usize + 1 {
+                log::warn!("skipping fallback font because the glyph ids do not fit");
+                continue;
+            }
+            let scale = match fallbacks.first() {
+                Some(primary) => primary.font.height_unscaled() / font.height_unscaled(),
+                None => 1.0,
+            };
+            let glyph_count = font.glyph_count();
+            fallbacks.push(Fallback {
+                font,
+                offset: offset as u16,
+                scale,
+            });
+            offset += glyph_count;
+        }
+        FallbackFont { fonts: fallbacks }
+    }
+
+    // 文字を持つ最初のフォントの番号と、そのフォントでの glyph id を返す
+    // どのフォントにもない文字は先頭のフォントの .notdef にする
+    pub(crate) fn glyph_for(&self, c: char) -> (usize, GlyphId) {
+        self.fonts
+            .iter()
+            .enumerate()
+            .find_map(|(index, fallback)| {
+                let id = fallback.font.glyph_id(c);
+                (id.0 != 0).then_some((index, id))
+            })
+            .unwrap_or((0, GlyphId(0)))
+    }
+
+    fn primary(&self) -> &F {
+        &self.fonts[0].font
+    }
+
+    fn locate(&self, id: GlyphId) -> (&Fallback<F>, GlyphId) {
+        let fallback = self
+            .fonts
+            .iter()
+            .rev()
+            .find(|fallback| fallback.offset <= id.0)
+            .unwrap_or(&self.fonts[0]);
+        (fallback, GlyphId(id.0 - fallback.offset))
+    }
+}
+
+impl<F: Font> Font for FallbackFont<F> {
+    fn units_per_em(&self) -> Option<f32> {
+        self.primary().units_per_em()
+    }
+
+    fn ascent_unscaled(&self) -> f32 {
+        self.primary().ascent_unscaled()
+    }
+
+    fn descent_unscaled(&self) -> f32 {
+        self.primary().descent_unscaled()
+    }
+
+    fn line_gap_unscaled(&self) -> f32 {
+        self.primary().line_gap_unscaled()
+    }
+
+    fn glyph_id(&self, c: char) -> GlyphId {
+        let (index, id) = self.glyph_for(c);
+        GlyphId(id.0 + self.fonts[index].offset)
+    }
+
+    fn h_advance_unscaled(&self, id: GlyphId) -> f32 {
+        let (fallback, id) = self.locate(id);
+        fallback.font.h_advance_unscaled(id) * fallback.scale
+    }
+
+    fn h_side_bearing_unscaled(&self, id: GlyphId) -> f32 {
+        let (fallback, id) = self.locate(id);
+        fallback.font.h_side_bearing_unscaled(id) * fallback.scale
+    }
+
+    fn v_advance_unscaled(&self, id: GlyphId) -> f32 {
+        let (fallback, id) = self.locate(id);
+        fallback.font.v_advance_unscaled(id) * fallback.scale
+    }
+
+    fn v_side_bearing_unscaled(&self, id: GlyphId) -> f32 {
+        let (fallback, id) = self.locate(id);
+        fallback.font.v_side_bearing_unscaled(id) * fallback.scale
+    }
+
+    // 違うフォントの文字の間ではカーニングしない
+    fn kern_unscaled(&self, first: GlyphId, second: GlyphId) -> f32 {
+        let (first_font, first) = self.locate(first);
+        let (second_font, second) = self.locate(second);
+        if first_font.offset != second_font.offset {
+            return 0.0;
+        }
+        first_font.font.kern_unscaled(first, second) * first_font.scale
+    }
+
+    fn outline(&self, id: GlyphId) -> Option<Outline> {
+        let (fallback, id) = self.locate(id);
+        let outline = fallback.font.outline(id)?;
+        if fallback.scale == 1.0 {
+            return Some(outline);
+        }
+        let scale = |p: Point| point(p.x * fallback.scale, p.y * fallback.scale);
+        Some(Outline {
+            bounds: ab_glyph::Rect {
+                min: scale(outline.bounds.min),
+                max: scale(outline.bounds.max),
+            },
+            curves: outline
+                .curves
+                .into_iter()
+                .map(|curve| match curve {
+                    OutlineCurve::Line(a, b) => OutlineCurve::Line(scale(a), scale(b)),
+                    OutlineCurve::Quad(a, b, c) => OutlineCurve::Quad(scale(a), scale(b), scale(c)),
+                    OutlineCurve::Cubic(a, b, c, d) => {
+                        OutlineCurve::Cubic(scale(a), scale(b), scale(c), scale(d))
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    fn glyph_count(&self) -> usize {
+        self.fonts
+            .iter()
+            .map(|fallback| fallback.font.glyph_count())
+            .sum()
+    }
+
+    // 描画では使わないので先頭のフォントの対応だけを返す
+    fn codepoint_ids(&self) -> ab_glyph::CodepointIdIter<'_> {
+        self.primary().codepoint_ids()
+    }
+
+    fn glyph_raster_image2(
+        &self,
+        id: GlyphId,
+        pixel_size: u16,
+    ) -> Option<ab_glyph::v2::GlyphImage<'_>> {
+        let (fallback, id) = self.locate(id);
+        fallback.font.glyph_raster_image2(id, pixel_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ab_glyph::FontRef;
+
+    const TEST_FONT: &[u8] = include_bytes!("../testdata/DejaVuSans.ttf");
+
+    fn test_font() -> FontRef<'static> {
+        FontRef::try_from_slice(TEST_FONT).unwrap()
+    }
+
+    #[test]
+    fn fallback_font_offsets_later_fonts() {
+        let font = test_font();
+        let fallback = FallbackFont::new(vec![test_font(), test_font()]);
+        assert_eq!(fallback.glyph_count(), font.glyph_count() * 2);
+        assert_eq!(fallback.glyph_id('a'), font.glyph_id('a'));
+        assert_eq!(fallback.glyph_id('\u{10FFFF}'), GlyphId(0));
+
+        // 後ろのフォントの glyph は元のフォントの値をそのまま返す
+        let id = font.glyph_id('a');
+        let shifted = GlyphId(id.0 + font.glyph_count() as u16);
+        assert_eq!(
+            fallback.h_advance_unscaled(shifted),
+            font.h_advance_unscaled(id)
+        );
+        assert_eq!(
+            fallback.outline(shifted).unwrap().bounds,
+            font.outline(id).unwrap().bounds
+        );
+        assert_eq!(fallback.kern_unscaled(id, shifted), 0.0);
+    }
+
+    #[test]
+    fn font_collection_puts_field_font_first() {
+        let mut fonts =
+            FontCollection::new(vec![FontVec::try_from_vec(TEST_FONT.to_vec()).unwrap()]);
+        let field = fonts.push(FontVec::try_from_vec(TEST_FONT.to_vec()).unwrap());
+        let font = test_font();
+
+        let fallback = fonts.fallback(None);
+        assert_eq!(fallback.fonts.len(), 1);
+        assert_eq!(fallback.glyph_for('a'), (0, font.glyph_id('a')));
+        assert_eq!(fallback.glyph_for('\u{10FFFF}'), (0, GlyphId(0)));
+
+        let fallback = fonts.fallback(Some(field));
+        assert_eq!(fallback.fonts.len(), 2);
+        assert_eq!(fallback.glyph_count(), font.glyph_count() * 2);
+        assert_eq!(fallback.glyph_for('a'), (0, font.glyph_id('a')));
+    }
+
+    #[test]
+    fn fallback_font_skips_fonts_beyond_glyph_id_range() {
+        let fonts = (0..20).map(|_| test_font()).collect();
+        let fallback = FallbackFont::new(fonts);
+        assert!(fallback.glyph_count() <= u16::MAX as usize + 1);
+        assert!(fallback.fonts.len() < 20);
+    }
+}
@@ -1,264 +1,7849 @@
-use ab_glyph::{point, Font, FontRef, Glyph, Point, PxScale, ScaleFont};
-use image::{ImageBuffer, Rgba};
-use log;
+mod fallback_font;
+mod zip;
+
+use fallback_font::FontCollection;
+
+use ab_glyph::{point, Font, FontVec, Glyph, GlyphId, Point, PxScale, Rect, ScaleFont};
+use futures_util::future::{join_all, LocalBoxFuture, Shared};
+use futures_util::FutureExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageBuffer, Rgb, Rgba};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+use unicode_normalization::UnicodeNormalization;
 use worker::*;
 
 const IMAGE_WIDTH: u32 = 1200;
 const IMAGE_HEIGHT: u32 = 630;
+const MIN_IMAGE_SIZE: u32 = 200;
+const MAX_IMAGE_SIZE: u32 = 2400;
+
+const DEFAULT_QUALITY: u8 = 80;
+
+const MAX_ITEMS: usize = 10;
+
+const MAX_BLUR: f32 = 20.0;
+const MAX_STROKE_WIDTH: f32 = 8.0;
+const MAX_OFFSET: f32 = 1000.0;
+
+const MIN_DPI: u32 = 72;
+const MAX_DPI: u32 = 1200;
+// print=1 ではこの幅まで拡大し、メモリを使いすぎないように画素数も制限する
+const PRINT_WIDTH: u32 = 3600;
+const PRINT_DPI: u32 = 300;
+const MAX_PRINT_PIXELS: u32 = 3600 * 2400;
+const MAX_RENDER_COST: f32 = 100_000_000.0;
+
+const TITLE_FONT_SIZE: f32 = 60.0;
+const TEXT_FONT_SIZE: f32 = 70.0;
+const STAT_FONT_SIZE: f32 = 96.0;
+const STAT_LABEL_FONT_SIZE: f32 = 36.0;
+const DEFAULT_TITLE_MAX_LINES: usize = 2;
+const DEFAULT_TITLE_MIN_SIZE: f32 = 36.0;
+// fit=width で拡大するときの上限
+const MAX_FIT_TITLE_SIZE: f32 = 240.0;
+// fit=width で求めた大きさの誤差で最後の文字だけが折り返されないように足す幅
+const FIT_WIDTH_TOLERANCE: f32 = 1.0;
+const MIN_TEXT_FONT_SIZE: f32 = 36.0;
+
+const TEXT_MARGIN: f32 = 80.0;
+const TEXT_GAP: f32 = 20.0;
+const COLUMN_GUTTER: f32 = 40.0;
+const PANEL_PADDING: f32 = 24.0;
+const DEBUG_LINE_WIDTH: f32 = 2.0;
+// 余白の内側は青、文字の範囲はマゼンタで描く
+const DEBUG_MARGIN_COLOR: (u8, u8, u8) = (0, 128, 255);
+const DEBUG_TEXT_COLOR: (u8, u8, u8) = (255, 0, 255);
+const WATERMARK_FONT_SIZE: f32 = 48.0;
+const WATERMARK_OPACITY: f32 = 0.12;
+// 右上がりに傾ける角度 (度)
+const WATERMARK_ANGLE: f32 = 30.0;
+const PANEL_RADIUS: f32 = 16.0;
+// accent の棒の長さと太さ、棒どうしと title との間隔
+const ACCENT_LENGTH: f32 = 48.0;
+const ACCENT_THICKNESS: f32 = 8.0;
+const ACCENT_GAP: f32 = 12.0;
+const MAX_ACCENT_COUNT: u8 = 3;
+const DEFAULT_MARGIN_LEFT: f32 = 80.0;
+const DEFAULT_MARGIN_RIGHT: f32 = 100.0;
+
+const DEFAULT_TEXT_COLOR: (u8, u8, u8) = (0, 0, 0);
+const DEFAULT_BACKGROUND_COLOR: (u8, u8, u8) = (255, 255, 255);
+const DEFAULT_HIGHLIGHT_COLOR: (u8, u8, u8) = (0xe5, 0x3e, 0x3e);
+
+const FONT_KEY: &str = "MPLUS1p-Medium.ttf";
+// R2 からフォントを読み込めないときに使う DejaVu Sans のサブセット (ASCII と … •)
+const EMBEDDED_FONT: &[u8] = include_bytes!("../assets/DejaVuSans-Subset.ttf");
+
+const MAX_BGIMAGE_LENGTH: usize = 5 * 1024 * 1024;
+const MAX_BGIMAGE_SIZE: u32 = 4096;
+
+const MAX_QUERY_LENGTH: usize = 4096;
+const MAX_BODY_LENGTH: usize = 16 * 1024;
+const MAX_BATCH_BODY_LENGTH: usize = 512 * 1024;
+const MAX_BATCH_ENTRIES: usize = 50;
 
 const INTERNAL_SERVER_ERROR: &str = "Internal Server Error";
 
-fn query(req: &Request, key: &str) -> Option<String> {
-    req.url()
-        .ok()?
-        .query_pairs()
+// 1 week
+const DEFAULT_CACHE_MAX_AGE: u64 = 604800;
+
+// パラメータの誤りは URL だけで決まるので、同じ URL を繰り返し叩かれても検証し直さないように短く保存する
+const BAD_REQUEST_CACHE_CONTROL: &str = "public, max-age=60";
+
+fn query(input: &[(String, String)], key: &str) -> Option<String> {
+    input
+        .iter()
         .find(|(k, _)| k == key)
         .map(|(_, v)| v.to_string())
 }
 
-#[event(fetch)]
-async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    if req.method() != Method::Get {
-        return Response::error("Method Not Allowed".to_string(), 405);
+fn query_required(input: &[(String, String)], key: &str) -> std::result::Result<String, String> {
+    match query(input, key) {
+        Some(value) if value.trim().is_empty() => {
+            Err(format!("{key} must contain non-whitespace characters"))
+        }
+        Some(value) => Ok(value),
+        None => Err(format!("{key} parameter is required")),
     }
+}
 
-    let url = match req.url() {
-        Ok(url) => url,
-        _ => {
-            log::error!("failed to get url");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+fn query_number<T: std::str::FromStr>(
+    input: &[(String, String)],
+    key: &str,
+) -> std::result::Result<Option<T>, String> {
+    match query(input, key) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(format!("{key} parameter is invalid")),
+        },
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum NumberError {
+    Invalid,
+    OutOfRange { min: u8, max: u8 },
+}
+
+impl NumberError {
+    fn message(&self, key: &str) -> String {
+        match self {
+            NumberError::Invalid => format!("{key} parameter is invalid"),
+            NumberError::OutOfRange { min, max } => {
+                format!("{key} must be between {min} and {max}")
+            }
         }
+    }
+}
+
+// 範囲外の値は丸めずにエラーにする (u8 に収まらない値も範囲外として扱う)
+fn parse_u8_in_range(s: &str, min: u8, max: u8) -> std::result::Result<u8, NumberError> {
+    let value = match s.trim().parse::<i64>() {
+        Ok(value) => value,
+        Err(_) => return Err(NumberError::Invalid),
     };
-    let cache = Cache::default();
-    let cache_key = CacheKey::Url(url.to_string());
+    match u8::try_from(value) {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(NumberError::OutOfRange { min, max }),
+    }
+}
 
-    let cached = match cache.get(cache_key, false).await {
-        Ok(cached) => cached,
-        Err(e) => {
-            log::error!("failed to get cache: {e}");
-            None
-        }
+fn query_u8(
+    input: &[(String, String)],
+    key: &str,
+    min: u8,
+    max: u8,
+) -> std::result::Result<Option<u8>, String> {
+    match query(input, key) {
+        Some(value) => parse_u8_in_range(&value, min, max)
+            .map(Some)
+            .map_err(|e| e.message(key)),
+        None => Ok(None),
+    }
+}
+
+// 列挙型のパラメータを from_param で読み、省略されていれば default にする
+fn parse_enum_param<T>(
+    input: &[(String, String)],
+    key: &str,
+    from_param: impl Fn(&str) -> Option<T>,
+    default: T,
+) -> std::result::Result<T, String> {
+    match query(input, key) {
+        Some(value) => from_param(&value).ok_or(format!("{key} parameter is invalid")),
+        None => Ok(default),
+    }
+}
+
+fn query_flag(input: &[(String, String)], key: &str) -> bool {
+    matches!(query(input, key).as_deref(), Some("1") | Some("true"))
+}
+
+// query_pairs() は不正な UTF-8 を U+FFFD に置き換えてしまうので自前でデコードする
+fn input_from_url(url: &Url) -> std::result::Result<Vec<(String, String)>, String> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        percent_encoding::percent_decode_str(&s)
+            .decode_utf8()
+            .map(|s| s.into_owned())
     };
-    match cached {
-        Some(cached) => {
-            return Ok(cached);
-        }
-        None => {
-            log::info!("cache not found (url = {url})");
-        }
+    url.query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            let k = match decode(k) {
+                Ok(k) => k,
+                Err(_) => return Err("query string is not valid UTF-8".to_string()),
+            };
+            match decode(v) {
+                Ok(v) => Ok((k, v)),
+                Err(_) => Err(format!("{k} parameter is not valid UTF-8")),
+            }
+        })
+        .collect()
+}
+
+// POST の JSON body をクエリパラメータと同じ形に変換する
+fn input_from_json(body: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let value: serde_json::Value = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return Err("request body is not valid JSON".to_string()),
+    };
+    match value {
+        serde_json::Value::Object(object) => input_from_object(object),
+        _ => Err("request body must be a JSON object".to_string()),
     }
+}
 
-    let text = match query(&req, "text") {
-        Some(text) => {
-            if text.len() > 150 {
-                return Response::error("text parameter is too long".to_string(), 400);
+fn input_from_object(
+    object: serde_json::Map<String, serde_json::Value>,
+) -> std::result::Result<Vec<(String, String)>, String> {
+    let mut input = Vec::new();
+    for (key, value) in object {
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                serde_json::Value::Null => {}
+                serde_json::Value::String(value) => input.push((key.clone(), value)),
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                    input.push((key.clone(), value.to_string()))
+                }
+                _ => return Err(format!("{key} must be a string, number or boolean")),
             }
-            text
         }
-        None => {
-            return Response::error("text parameter is required".to_string(), 400);
+    }
+    Ok(input)
+}
+
+// 既定の v に加えて CACHE_IGNORED_PARAMS (カンマ区切り) で指定したものを無視する
+fn ignored_cache_params(env: &Env) -> Vec<String> {
+    let mut ignored = vec!["v".to_string()];
+    ignored.extend(env_list(env, "CACHE_IGNORED_PARAMS"));
+    ignored
+}
+
+// パラメータの順番が違うだけのリクエストで同じキャッシュを使えるように並べ替える
+// (同じキーが複数ある場合はその順番を保つ)
+// ignored に含まれるパラメータ (キャッシュバスター用の v など) はキーに含めない
+fn cache_key(url: &Url, input: &[(String, String)], ignored: &[String]) -> String {
+    let mut pairs: Vec<&(String, String)> =
+        input.iter().filter(|(k, _)| !ignored.contains(k)).collect();
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut key = url.clone();
+    key.set_fragment(None);
+    key.set_query(None);
+    if !pairs.is_empty() {
+        key.query_pairs_mut()
+            .extend_pairs(pairs.iter().map(|(k, v)| (k, v)));
+    }
+    key.to_string()
+}
+
+// POST で受け取ったパラメータも GET で取得できる URL にする
+fn image_url(url: &Url, input: &[(String, String)]) -> String {
+    let mut image_url = url.clone();
+    image_url.set_fragment(None);
+    image_url.set_query(None);
+    if !input.is_empty() {
+        image_url
+            .query_pairs_mut()
+            .extend_pairs(input.iter().map(|(k, v)| (k, v)));
+    }
+    image_url.to_string()
+}
+
+// そのまま <head> に貼れる OGP と Twitter Card の meta タグ
+fn meta_tags(params: &Params, image_url: &str) -> String {
+    let (width, height) = params.canvas_size();
+    let description = params.description();
+    let mut tags = vec![
+        ("property", "og:title", params.title.clone()),
+        ("property", "og:image", image_url.to_string()),
+        (
+            "property",
+            "og:image:type",
+            params.format.content_type().to_string(),
+        ),
+        ("property", "og:image:width", width.to_string()),
+        ("property", "og:image:height", height.to_string()),
+        ("property", "og:image:alt", params.alt_text()),
+        ("name", "twitter:card", "summary_large_image".to_string()),
+        ("name", "twitter:title", params.title.clone()),
+        ("name", "twitter:image", image_url.to_string()),
+        ("name", "twitter:image:alt", params.alt_text()),
+    ];
+    if !description.is_empty() {
+        tags.push(("property", "og:description", description.clone()));
+        tags.push(("name", "twitter:description", description));
+    }
+    if let Some(author) = &params.author {
+        tags.push(("name", "author", author.clone()));
+    }
+    tags.iter()
+        .map(|(attribute, name, content)| {
+            format!(
+                r#"<meta {attribute}="{name}" content="{}">"#,
+                escape_xml(content)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// "rrggbb" または "#rrggbb" 形式
+fn parse_color(s: &str) -> Option<(u8, u8, u8)> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.len() != 6 || !s.is_ascii() {
+        return None;
+    }
+    let r = u8::from_str_radix(&s[0..2], 16).ok()?;
+    let g = u8::from_str_radix(&s[2..4], 16).ok()?;
+    let b = u8::from_str_radix(&s[4..6], 16).ok()?;
+    Some((r, g, b))
+}
+
+// px か、描画する文字の大きさを基準にした em で指定する長さ
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Length {
+    Px(f32),
+    Em(f32),
+}
+
+impl Length {
+    fn from_param(s: &str) -> Option<Self> {
+        let length = match s.strip_suffix("em") {
+            Some(em) => Length::Em(em.trim().parse().ok()?),
+            None => Length::Px(s.strip_suffix("px").unwrap_or(s).trim().parse().ok()?),
+        };
+        match length {
+            Length::Px(value) | Length::Em(value) if value.is_finite() => Some(length),
+            _ => None,
         }
-    };
-    let author = match query(&req, "author") {
-        Some(author) => author,
-        None => {
-            return Response::error("author parameter is required".to_string(), 400);
+    }
+
+    fn resolve(self, font_size: f32) -> f32 {
+        match self {
+            Length::Px(px) => px,
+            Length::Em(em) => em * font_size,
         }
-    };
-    let title = match query(&req, "title") {
-        Some(title) => title,
+    }
+}
+
+fn query_length(
+    input: &[(String, String)],
+    key: &str,
+) -> std::result::Result<Option<Length>, String> {
+    match query(input, key) {
+        Some(value) => Length::from_param(&value)
+            .map(Some)
+            .ok_or(format!("{key} parameter is invalid")),
+        None => Ok(None),
+    }
+}
+
+// rrggbbaa の形式で不透明度も指定する
+fn parse_color_alpha(s: &str) -> Option<((u8, u8, u8), u8)> {
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.len() != 8 || !s.is_ascii() {
+        return None;
+    }
+    let alpha = u8::from_str_radix(&s[6..8], 16).ok()?;
+    Some((parse_color(&s[0..6])?, alpha))
+}
+
+fn query_color(
+    input: &[(String, String)],
+    key: &str,
+    default: (u8, u8, u8),
+) -> std::result::Result<(u8, u8, u8), String> {
+    match query(input, key) {
+        Some(value) => parse_color(&value).ok_or(format!("{key} parameter is invalid")),
+        None => Ok(default),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+struct Variant {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<OutputFormat>,
+    quality: Option<u8>,
+}
+
+fn builtin_variant(name: &str) -> Option<Variant> {
+    match name {
+        "og" => Some(Variant {
+            width: Some(1200),
+            height: Some(630),
+            format: Some(OutputFormat::Png),
+            quality: None,
+        }),
+        "twitter" => Some(Variant {
+            width: Some(1200),
+            height: Some(600),
+            format: Some(OutputFormat::Png),
+            quality: None,
+        }),
+        "square" => Some(Variant {
+            width: Some(1200),
+            height: Some(1200),
+            format: Some(OutputFormat::Png),
+            quality: None,
+        }),
+        _ => None,
+    }
+}
+
+static VARIANTS: OnceLock<HashMap<String, Variant>> = OnceLock::new();
+
+// VARIANTS 環境変数 ({"name": {"width": ..., ...}}) の定義を組み込みのものより優先する
+fn resolve_variant(env: &Env, name: &str) -> Option<Variant> {
+    // THEMES と同じく isolate ごとに 1 回だけパースする
+    let variants = VARIANTS.get_or_init(|| match env.var("VARIANTS") {
+        Ok(variants) => match serde_json::from_str(&variants.to_string()) {
+            Ok(variants) => variants,
+            Err(e) => {
+                log::error!("failed to parse VARIANTS: {e}");
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    });
+    variants
+        .get(name)
+        .copied()
+        .or_else(|| builtin_variant(name))
+}
+
+// THEMES 環境変数 ({"name": {"font": "...", "bg": "...", "dark": {...}, ...}}) で定義する
+// font と dark 以外はパラメータの既定値として扱い、リクエストで指定された値を優先する
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Theme {
+    font: Option<String>,
+    // mode=dark のときにほかの値より優先する
+    dark: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(flatten)]
+    params: serde_json::Map<String, serde_json::Value>,
+}
+
+static THEMES: OnceLock<HashMap<String, Theme>> = OnceLock::new();
+
+fn resolve_theme(env: &Env, name: &str) -> std::result::Result<Theme, String> {
+    // 環境変数はデプロイ中に変わらないので isolate ごとに 1 回だけパースする
+    let themes = THEMES.get_or_init(|| match env.var("THEMES") {
+        Ok(themes) => match serde_json::from_str(&themes.to_string()) {
+            Ok(themes) => themes,
+            Err(e) => {
+                log::error!("failed to parse THEMES: {e}");
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    });
+    find_theme(themes, name)
+}
+
+fn find_theme(themes: &HashMap<String, Theme>, name: &str) -> std::result::Result<Theme, String> {
+    match themes.get(name) {
+        Some(theme) => Ok(theme.clone()),
+        None if themes.is_empty() => Err(format!("theme {name} is not defined")),
         None => {
-            return Response::error("title parameter is required".to_string(), 400);
+            let mut names: Vec<&str> = themes.keys().map(String::as_str).collect();
+            names.sort();
+            Err(format!(
+                "theme {name} is not defined (available: {})",
+                names.join(", ")
+            ))
         }
-    };
+    }
+}
 
-    let bucket = match env.bucket("BUCKET") {
-        Ok(bucket) => bucket,
-        Err(e) => {
-            log::error!("failed to get bucket: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ColorMode {
+    Light,
+    Dark,
+}
+
+impl ColorMode {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "light" => Some(ColorMode::Light),
+            "dark" => Some(ColorMode::Dark),
+            _ => None,
         }
-    };
-    let raw_font = match bucket.get("MPLUS1p-Medium.ttf").execute().await {
-        Ok(raw_font) => match raw_font {
-            Some(raw_font) => raw_font,
-            None => {
-                log::error!("font is not found");
-                return Response::error(INTERNAL_SERVER_ERROR.to_string(), 404);
+    }
+}
+
+// 画像のリクエストからはクライアントの設定を直接知れないので
+// mode=auto はページ側が colorscheme パラメータで渡した prefers-color-scheme に従う
+fn parse_color_mode(input: &[(String, String)]) -> std::result::Result<ColorMode, String> {
+    match query(input, "mode").as_deref() {
+        Some("auto") => match query(input, "colorscheme") {
+            Some(scheme) => {
+                ColorMode::from_param(&scheme).ok_or("colorscheme parameter is invalid".to_string())
             }
+            None => Ok(ColorMode::Light),
         },
-        Err(e) => {
-            log::error!("failed to get font: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        Some(mode) => ColorMode::from_param(mode).ok_or("mode parameter is invalid".to_string()),
+        None => Ok(ColorMode::Light),
+    }
+}
+
+fn apply_theme(
+    input: &mut Vec<(String, String)>,
+    theme: &Theme,
+    mode: ColorMode,
+) -> std::result::Result<(), String> {
+    if mode == ColorMode::Dark {
+        // テーマに dark がなければ組み込みの配色を使う
+        let dark = match &theme.dark {
+            Some(dark) => dark.clone(),
+            None => serde_json::Map::from_iter([
+                ("bg".to_string(), serde_json::Value::from("1e1e1e")),
+                ("color".to_string(), serde_json::Value::from("f0f0f0")),
+            ]),
+        };
+        apply_defaults(input, dark)?;
+    }
+    apply_defaults(input, theme.params.clone())
+}
+
+// query は最初に見つかった値を使うので、後ろに追加すればリクエストの値が優先される
+fn apply_defaults(
+    input: &mut Vec<(String, String)>,
+    defaults: serde_json::Map<String, serde_json::Value>,
+) -> std::result::Result<(), String> {
+    // item のように繰り返すパラメータが混ざらないよう、リクエストにあるキーは丸ごと除く
+    let defaults: Vec<(String, String)> = input_from_object(defaults)?
+        .into_iter()
+        .filter(|(k, _)| !input.iter().any(|(key, _)| key == k))
+        .collect();
+    input.extend(defaults);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Script {
+    Latin,
+    Cjk,
+    Cyrillic,
+}
+
+impl Script {
+    fn of(c: char) -> Option<Self> {
+        match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Some(Script::Latin),
+            '\u{0400}'..='\u{04FF}' => Some(Script::Cyrillic),
+            '\u{3040}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{AC00}'..='\u{D7AF}'
+            | '\u{FF66}'..='\u{FF9F}' => Some(Script::Cjk),
+            _ => None,
         }
-    };
-    let raw_font = raw_font.body().unwrap().bytes().await.unwrap();
+    }
+}
 
-    let font = match FontRef::try_from_slice(&raw_font) {
-        Ok(font) => font,
-        Err(e) => {
-            log::error!("failed to load font: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+// CJK や Cyrillic のフォントはたいてい Latin の文字も含むので、Latin との混在はそちらを優先する
+// それ以外の組み合わせは判定できないものとして扱う
+fn detect_script<'a>(texts: impl IntoIterator<Item = &'a str>) -> Option<Script> {
+    let mut found = Vec::new();
+    for c in texts.into_iter().flat_map(str::chars) {
+        if let Some(script) = Script::of(c) {
+            if !found.contains(&script) {
+                found.push(script);
+            }
         }
-    };
+    }
+    if found.len() > 1 {
+        found.retain(|&script| script != Script::Latin);
+    }
+    match found[..] {
+        [script] => Some(script),
+        _ => None,
+    }
+}
 
-    let mut imgbuf = ImageBuffer::from_pixel(IMAGE_WIDTH, IMAGE_HEIGHT, Rgba([255, 255, 255, 255]));
-    imgbuf = render_text(
-        font.clone(),
-        PxScale::from(70.0),
-        imgbuf,
-        &text,
-        (0, 0, 0),
-        point(80.0, 230.0),
-    );
-    imgbuf = render_text(
-        font.clone(),
-        PxScale::from(60.0),
-        imgbuf,
-        &title,
-        (0, 0, 0),
-        point(80.0, 80.0),
-    );
-    imgbuf = render_text(
-        font.clone(),
-        PxScale::from(60.0),
-        imgbuf,
-        &author,
-        (0, 0, 0),
-        point(1000.0, 500.0),
-    );
+static SCRIPT_FONTS: OnceLock<HashMap<Script, String>> = OnceLock::new();
 
-    let mut buffer = std::io::Cursor::new(vec![]);
-    match imgbuf.write_to(&mut buffer, image::ImageFormat::Png) {
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("failed to write image: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+// SCRIPT_FONTS 環境変数 ({"cjk": "...", "latin": "..."}) から文字種に合うフォントを選ぶ
+fn resolve_script_font(env: &Env, script: Script) -> Option<String> {
+    // THEMES と同じく isolate ごとに 1 回だけパースする
+    let fonts = SCRIPT_FONTS.get_or_init(|| match env.var("SCRIPT_FONTS") {
+        Ok(fonts) => match serde_json::from_str(&fonts.to_string()) {
+            Ok(fonts) => fonts,
+            Err(e) => {
+                log::error!("failed to parse SCRIPT_FONTS: {e}");
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    });
+    fonts.get(&script).cloned()
+}
+
+// CHARSET 環境変数 ({"font": "...", "ranges": ["0020-007e"]}) でサブセットにしたフォントを指定する
+#[derive(Deserialize)]
+struct Charset {
+    font: String,
+    ranges: Vec<String>,
+}
+
+// サブセットのフォントと、パースした文字の範囲
+type SubsetFont = (String, Vec<RangeInclusive<u32>>);
+
+static SUBSET_FONT: OnceLock<Option<SubsetFont>> = OnceLock::new();
+
+// 描画する文字がすべてサブセットに含まれていれば小さい方のフォントを使う
+// 含まれない文字があれば既定のフォントだけを読み込む (同じ字形を 2 つ取得しないため)
+fn resolve_subset_font<'a>(env: &Env, texts: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    // THEMES と同じく isolate ごとに 1 回だけパースする
+    let (font, ranges) = SUBSET_FONT
+        .get_or_init(|| {
+            let charset = env.var("CHARSET").ok()?.to_string();
+            let charset = match serde_json::from_str::<Charset>(&charset) {
+                Ok(charset) => charset,
+                Err(e) => {
+                    log::error!("failed to parse CHARSET: {e}");
+                    return None;
+                }
+            };
+            match parse_char_ranges(&charset.ranges) {
+                Some(ranges) => Some((charset.font, ranges)),
+                None => {
+                    log::error!("CHARSET has invalid ranges: {:?}", charset.ranges);
+                    None
+                }
+            }
+        })
+        .as_ref()?;
+    charset_covers(ranges, texts).then(|| font.clone())
+}
+
+// "0020-007e" のような 16 進の範囲か "00a9" のような 1 文字を受け付ける
+fn parse_char_ranges(ranges: &[String]) -> Option<Vec<RangeInclusive<u32>>> {
+    ranges
+        .iter()
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start = u32::from_str_radix(start.trim(), 16).ok()?;
+            let end = u32::from_str_radix(end.trim(), 16).ok()?;
+            (start <= end).then_some(start..=end)
+        })
+        .collect()
+}
+
+fn charset_covers<'a>(
+    ranges: &[RangeInclusive<u32>],
+    texts: impl IntoIterator<Item = &'a str>,
+) -> bool {
+    texts
+        .into_iter()
+        .flat_map(str::chars)
+        .all(|c| c.is_control() || ranges.iter().any(|range| range.contains(&(c as u32))))
+}
+
+// 機能を切り替える環境変数は 1 か true で有効にする
+fn env_flag(env: &Env, name: &str) -> bool {
+    env.var(name)
+        .is_ok_and(|value| matches!(value.to_string().as_str(), "1" | "true"))
+}
+
+// カンマ区切りの環境変数を、空の要素を除いて前後の空白を取り除いた値の列にする
+fn env_list(env: &Env, name: &str) -> Vec<String> {
+    match env.var(name) {
+        Ok(value) => value
+            .to_string()
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Authorization: Bearer <secret> と一致するかを確かめる
+fn bearer_authorized(req: &Request, secret: &str) -> bool {
+    match req.headers().get("Authorization") {
+        Ok(Some(value)) => {
+            constant_time_eq(value.as_bytes(), format!("Bearer {secret}").as_bytes())
         }
+        _ => false,
     }
+}
 
-    let resp = match Response::from_bytes(buffer.into_inner()) {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("failed to create response: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+// FALLBACK_FONTS (カンマ区切り) のフォントは先頭のフォントにない文字に使う
+fn fallback_font_keys(env: &Env) -> Vec<String> {
+    env_list(env, "FALLBACK_FONTS")
+}
+
+// titlefont などに指定できるのは FONTS (カンマ区切り) に並べたフォントだけにする
+fn allowed_field_fonts(env: &Env) -> Vec<String> {
+    env_list(env, "FONTS")
+}
+
+// 本番のカードに枠が出ないように DEBUG を設定した環境でだけ debug=bounds を許す
+fn check_debug(params: &Params, env: &Env) -> std::result::Result<(), String> {
+    if params.debug_bounds && !env_flag(env, "DEBUG") {
+        return Err("debug parameter is not allowed".to_string());
+    }
+    Ok(())
+}
+
+fn check_field_fonts(params: &Params, allowed: &[String]) -> std::result::Result<(), String> {
+    for (name, key) in [
+        ("titlefont", &params.title_font),
+        ("textfont", &params.text_font),
+        ("authorfont", &params.author_font),
+    ] {
+        if key.as_ref().is_some_and(|key| !allowed.contains(key)) {
+            return Err(format!("{name} is not allowed"));
         }
-    };
-    let mut headers = Headers::new();
-    match headers.set("content-type", "image/png") {
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("failed to set content-type header: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+    }
+    Ok(())
+}
+
+// BGIMAGE_HOSTS (カンマ区切り) が設定されていなければ外部の画像は使えない
+fn allowed_bgimage_hosts(env: &Env) -> Vec<String> {
+    env_list(env, "BGIMAGE_HOSTS")
+        .into_iter()
+        .map(|host| host.to_ascii_lowercase())
+        .collect()
+}
+
+fn is_allowed_host(url: &Url, hosts: &[String]) -> bool {
+    url.host_str()
+        .is_some_and(|host| hosts.iter().any(|allowed| allowed == host))
+}
+
+// 許可していないホストへ転送されないようにリダイレクトはエラーにする
+async fn fetch_remote_image(url: &Url) -> std::result::Result<DynamicImage, String> {
+    let mut init = RequestInit::new();
+    init.with_redirect(RequestRedirect::Error);
+    let req = Request::new_with_init(url.as_str(), &init).map_err(|e| e.to_string())?;
+    let mut resp = Fetch::Request(req)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if resp.status_code() != 200 {
+        return Err(format!("unexpected status {}", resp.status_code()));
+    }
+    if let Ok(Some(length)) = resp.headers().get("Content-Length") {
+        if length
+            .parse::<usize>()
+            .is_ok_and(|length| length > MAX_BGIMAGE_LENGTH)
+        {
+            return Err(format!("image is larger than {MAX_BGIMAGE_LENGTH} bytes"));
         }
+    }
+    let bytes = resp.bytes().await.map_err(|e| e.to_string())?;
+    decode_remote_image(&bytes)
+}
+
+fn decode_remote_image(bytes: &[u8]) -> std::result::Result<DynamicImage, String> {
+    if bytes.len() > MAX_BGIMAGE_LENGTH {
+        return Err(format!("image is larger than {MAX_BGIMAGE_LENGTH} bytes"));
+    }
+    let mut reader = image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_BGIMAGE_SIZE);
+    limits.max_image_height = Some(MAX_BGIMAGE_SIZE);
+    reader.limits(limits);
+    reader.decode().map_err(|e| e.to_string())
+}
+
+// フォントと template は別のバケットに置ける (FONT_BUCKET, ASSET_BUCKET)
+// 分けていない環境では今までどおり BUCKET だけを使う
+struct Buckets {
+    fonts: Bucket,
+    assets: Bucket,
+}
+
+impl Buckets {
+    fn from_env(env: &Env) -> Result<Self> {
+        Ok(Buckets {
+            fonts: font_bucket(env)?,
+            assets: env
+                .bucket("ASSET_BUCKET")
+                .or_else(|_| env.bucket("BUCKET"))?,
+        })
+    }
+}
+
+fn font_bucket(env: &Env) -> Result<Bucket> {
+    env.bucket("FONT_BUCKET").or_else(|_| env.bucket("BUCKET"))
+}
+
+async fn fetch_object(bucket: &Bucket, key: &str) -> Result<Option<Vec<u8>>> {
+    let object = match bucket.get(key).execute().await? {
+        Some(object) => object,
+        None => return Ok(None),
     };
-    match headers.set("Cache-Control", "public, max-age=604800") {
-        // 1 week
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("failed to set Cache-Control header: {e}");
-            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
-        }
+    match object.body() {
+        Some(body) => Ok(Some(body.bytes().await?)),
+        None => Ok(None),
+    }
+}
+
+// 読み込めなかったフォントが別の形式のファイルなのか、壊れているのかを判別するために使う
+fn font_signature(bytes: &[u8]) -> &'static str {
+    match bytes.get(..4) {
+        Some([0x00, 0x01, 0x00, 0x00]) | Some(b"true") => "TrueType signature",
+        Some(b"OTTO") => "OpenType (CFF) signature",
+        Some(b"ttcf") => "font collection signature",
+        Some(b"wOFF") | Some(b"wOF2") => "WOFF signature, which is not supported",
+        Some(_) => "unknown signature",
+        None => "too short to have a signature",
+    }
+}
+
+// /font で返すときの content-type (IANA の font/* を使う)
+fn font_content_type(bytes: &[u8]) -> &'static str {
+    match bytes.get(..4) {
+        Some([0x00, 0x01, 0x00, 0x00]) | Some(b"true") => "font/ttf",
+        Some(b"OTTO") => "font/otf",
+        Some(b"ttcf") => "font/collection",
+        Some(b"wOFF") => "font/woff",
+        Some(b"wOF2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+// R2 に置いたフォントがどの版かを確かめるために、描画と同じ経路で取得した中身をそのまま返す
+// key で既定のフォント以外も指定できるが、FALLBACK_FONTS と FONTS に並べたものに限る
+// ライセンスのあるフォントを配らないよう、/purge と同じ PURGE_SECRET の Bearer token を求める
+async fn serve_font(req: &Request, env: &Env, url: &Url) -> Result<Response> {
+    if !env_flag(env, "FONT_ROUTE") {
+        return Response::error("Not Found".to_string(), 404);
+    }
+    let secret = match env.secret("PURGE_SECRET") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Response::error("Not Found".to_string(), 404),
     };
-    let mut resp = resp.with_headers(headers);
-    let cloned_resp = match resp.cloned() {
-        Ok(cloned_resp) => cloned_resp,
+    if !bearer_authorized(req, &secret) {
+        return Response::error("Unauthorized".to_string(), 401);
+    }
+    let input = match input_from_url(url) {
+        Ok(input) => input,
+        Err(e) => return Response::error(e, 400),
+    };
+    let key = query(&input, "key").unwrap_or(FONT_KEY.to_string());
+    if key != FONT_KEY
+        && !fallback_font_keys(env).contains(&key)
+        && !allowed_field_fonts(env).contains(&key)
+    {
+        return Response::error("key is not allowed".to_string(), 403);
+    }
+    let bucket = match font_bucket(env) {
+        Ok(bucket) => bucket,
         Err(e) => {
-            log::error!("failed to clone response: {e}");
+            log::error!("failed to get bucket: {e}");
             return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
         }
     };
-    match cache.put(url.to_string(), cloned_resp).await {
-        Ok(_) => {}
+    let raw_font = match fetch_fonts(&bucket, &[&key]).await.remove(0) {
+        Ok(Some(raw_font)) => raw_font,
+        Ok(None) => return Response::error(format!("font {key} is not found"), 404),
         Err(e) => {
-            // cache に保存できなくてもレスポンスは返す
-            log::error!("failed to put cache: {e}");
+            log::error!("failed to get font {key}: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
         }
     };
-
-    Ok(resp)
+    let mut headers = Headers::new();
+    headers.set("content-type", font_content_type(&raw_font))?;
+    // 置き直したフォントをすぐに確かめられるようにキャッシュしない
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_bytes(raw_font)?.with_headers(headers))
 }
 
-fn render_text<F: Font>(
-    font: F,
-    font_scale: PxScale,
-    imgbuf: ImageBuffer<Rgba<u8>, Vec<u8>>,
-    text: &str,
-    text_color: (u8, u8, u8),
-    text_position: Point,
-) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    let scaled_font = font.as_scaled(font_scale);
-
-    let mut glyphs = Vec::new();
-    layout_paragraph(
-        scaled_font,
-        text_position,
-        IMAGE_WIDTH as f32 - 180.0,
-        text,
-        &mut glyphs,
+// R2 へのリクエストは 1 回ずつ往復が発生するので並列に取得する
+async fn fetch_fonts(bucket: &Bucket, keys: &[&str]) -> Vec<Result<Option<Vec<u8>>>> {
+    let started = Date::now().as_millis();
+    let fonts = join_all(keys.iter().map(|key| fetch_object(bucket, key))).await;
+    log::info!(
+        "loaded {} font(s) in {}ms",
+        keys.len(),
+        Date::now().as_millis() - started
     );
+    fonts
+}
 
-    render_glyphs(font, glyphs, imgbuf, text_color)
+// 描画した結果ではなく正規化したパラメータ (キャッシュキー) から作る
+// DefaultHasher::new() は鍵が固定なので isolate をまたいでも同じ値になる
+fn params_etag(cache_url: &str) -> String {
+    use std::hash::{DefaultHasher, Hasher};
+    let mut hasher = DefaultHasher::new();
+    hasher.write(cache_url.as_bytes());
+    format!("\"{:016x}\"", hasher.finish())
 }
 
-fn render_glyphs<F: Font>(
-    font: F,
-    glyphs: Vec<Glyph>,
-    mut imgbuf: ImageBuffer<Rgba<u8>, Vec<u8>>,
-    text_color: (u8, u8, u8),
-) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    for glyph in glyphs {
-        if let Some(outlined) = font.outline_glyph(glyph) {
-            let bounds = outlined.px_bounds();
-            outlined.draw(|x, y, v| {
-                let px = imgbuf.get_pixel_mut(x + bounds.min.x as u32, y + bounds.min.y as u32);
-                *px = Rgba([
-                    (px.0[0] as f32 * (1.0 - v) + text_color.0 as f32 * v) as u8,
-                    (px.0[1] as f32 * (1.0 - v) + text_color.1 as f32 * v) as u8,
-                    (px.0[2] as f32 * (1.0 - v) + text_color.2 as f32 * v) as u8,
-                    255,
-                ]);
-            });
+// If-None-Match は弱い比較なので W/ を無視し、カンマ区切りのどれかと一致すればよい
+// * は GET では使わないので、ETag を知らないクライアントには 304 を返さない
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate.strip_prefix("W/").unwrap_or(candidate) == etag)
+}
+
+// 長さ以外の情報がタイミングから漏れないように比較する
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn purge(req: &Request, env: &Env, url: &Url) -> Result<Response> {
+    if req.method() != Method::Post {
+        return Response::error("Method Not Allowed".to_string(), 405);
+    }
+    // PURGE_SECRET が設定されていなければエンドポイント自体を無効にする
+    let secret = match env.secret("PURGE_SECRET") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Response::error("Not Found".to_string(), 404),
+    };
+    if !bearer_authorized(req, &secret) {
+        return Response::error("Unauthorized".to_string(), 401);
+    }
+
+    // url パラメータがなければ /purge のクエリをそのままカードのパラメータとして扱う
+    let input = match input_from_url(url) {
+        Ok(input) => input,
+        Err(e) => return Response::error(e, 400),
+    };
+    let target = match query(&input, "url") {
+        Some(target) => match Url::parse(&target) {
+            Ok(target) => target,
+            Err(_) => return Response::error("url parameter is invalid".to_string(), 400),
+        },
+        None => {
+            let mut target = url.clone();
+            target.set_path("/");
+            target
+        }
+    };
+    let target_input = match input_from_url(&target) {
+        Ok(target_input) => target_input,
+        Err(e) => return Response::error(e, 400),
+    };
+    let target = cache_key(&target, &target_input, &ignored_cache_params(env));
+    match Cache::default().delete(target.clone(), false).await {
+        Ok(CacheDeletionOutcome::Success) => {
+            log::info!("purged cache (url = {target})");
+            Response::ok("purged")
+        }
+        Ok(CacheDeletionOutcome::ResponseNotFound) => {
+            Response::error("cache not found".to_string(), 404)
+        }
+        Err(e) => {
+            log::error!("failed to delete cache: {e}");
+            Response::error(INTERNAL_SERVER_ERROR.to_string(), 500)
         }
     }
-    imgbuf
 }
 
-fn layout_paragraph<F, SF>(
-    font: SF,
-    position: Point,
+const CRAWLER_USER_AGENTS: &[&str] = &[
+    "facebookexternalhit",
+    "twitterbot",
+    "slackbot",
+    "linkedinbot",
+    "discordbot",
+    "telegrambot",
+    "whatsapp",
+    "skypeuripreview",
+    "pinterest",
+];
+
+fn is_crawler(user_agent: &str) -> bool {
+    let user_agent = user_agent.to_ascii_lowercase();
+    CRAWLER_USER_AGENTS
+        .iter()
+        .any(|crawler| user_agent.contains(crawler))
+}
+
+// パラメータの誤りをどの形式で返すか
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ErrorFormat {
+    // エラーの内容を描いた PNG
+    Image,
+    Json,
+    Text,
+}
+
+// 画像を一番に求めるクライアント (<img> など) にはエラーも画像で返す
+// ブラウザで開いたときは text/html と画像が同じ q になるのでテキストにする
+fn negotiate_error_format(accept: &str) -> ErrorFormat {
+    let (mut image, mut json, mut text) = (0.0f32, 0.0f32, 0.0f32);
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type.starts_with("image/") {
+            image = image.max(q);
+        } else if media_type == "application/json" {
+            json = json.max(q);
+        } else if media_type.starts_with("text/") {
+            text = text.max(q);
+        }
+    }
+    if image > 0.0 && image > json && image > text {
+        ErrorFormat::Image
+    } else if json > 0.0 && json > text {
+        ErrorFormat::Json
+    } else {
+        ErrorFormat::Text
+    }
+}
+
+// ERROR_CARDS 環境変数か errorcard パラメータで有効にする
+fn error_cards_enabled(env: &Env, input: &[(String, String)]) -> bool {
+    query_flag(input, "errorcard") || env_flag(env, "ERROR_CARDS")
+}
+
+// エラーカードの見出しは status から決める
+fn error_card_title(status: u16) -> &'static str {
+    match status {
+        400 | 422 => "Invalid request",
+        404 => "Not found",
+        413 | 414 => "Request too large",
+        _ => "Something went wrong",
+    }
+}
+
+// エラーの内容を描いた PNG を status で返す
+// 200 で返すとクローラーがエラーカードを本来の og:image として扱うので成功にはしない
+// 描けなかったときは status のテキストで返す
+fn error_card(message: &str, status: u16, cache_control: &str) -> Result<Response> {
+    let input = vec![
+        ("title".to_string(), error_card_title(status).to_string()),
+        ("text".to_string(), message.chars().take(100).collect()),
+    ];
+    let params = match parse_params(&input, Variant::default()) {
+        Ok(params) => params,
+        Err(e) => {
+            log::error!("failed to build error card: {e}");
+            return Response::error(message.to_string(), status);
+        }
+    };
+    // エラーの内容は ASCII なので、R2 から取得せずに組み込みのフォントで描く
+    let (fonts, _) = match parse_fonts(None, Vec::new()) {
+        Ok(fonts) => fonts,
+        Err(_) => return Response::error(message.to_string(), status),
+    };
+    let imgbuf = generate_card(&fonts.fallback(None), &params, None);
+    let buffer = match encode_image(&imgbuf, OutputFormat::Png, &params) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            log::error!("failed to write error card: {e}");
+            return Response::error(message.to_string(), status);
+        }
+    };
+    let mut headers = Headers::new();
+    headers.set("content-type", OutputFormat::Png.content_type())?;
+    headers.set("Cache-Control", cache_control)?;
+    Ok(Response::from_bytes(buffer)?
+        .with_status(status)
+        .with_headers(headers))
+}
+
+#[derive(Clone, Debug)]
+struct RenderError {
+    message: String,
+    status: u16,
+}
+
+impl RenderError {
+    fn new(message: String, status: u16) -> Self {
+        RenderError { message, status }
+    }
+
+    fn font_not_found() -> Self {
+        RenderError::new(FONT_NOT_FOUND.to_string(), 404)
+    }
+
+    fn is_font_not_found(&self) -> bool {
+        self.status == 404 && self.message == FONT_NOT_FOUND
+    }
+}
+
+const FONT_NOT_FOUND: &str = "font is not found";
+// フォントが見つからないときに代わりに返す画像 (ASSET_BUCKET に置く)
+const MISSING_FONT_IMAGE_KEY: &str = "missing.png";
+
+// MISSING_FONT_IMAGE を設定した環境では、設定の誤りでプレビューが壊れないように用意した画像を 200 で返す
+async fn missing_font_image(env: &Env) -> Option<Response> {
+    if !env_flag(env, "MISSING_FONT_IMAGE") {
+        return None;
+    }
+    let raw_image = match Buckets::from_env(env) {
+        Ok(buckets) => fetch_object(&buckets.assets, MISSING_FONT_IMAGE_KEY).await,
+        Err(e) => Err(e),
+    };
+    let raw_image = match raw_image {
+        Ok(Some(raw_image)) => raw_image,
+        Ok(None) => {
+            log::error!("{MISSING_FONT_IMAGE_KEY} is not found");
+            return None;
+        }
+        Err(e) => {
+            log::error!("failed to get {MISSING_FONT_IMAGE_KEY}: {e}");
+            return None;
+        }
+    };
+    let resp = Response::from_bytes(raw_image).and_then(|resp| {
+        let mut headers = Headers::new();
+        headers.set("content-type", OutputFormat::Png.content_type())?;
+        // フォントを置き直したらすぐに正しいカードを返せるようにキャッシュしない
+        headers.set("Cache-Control", "no-store")?;
+        Ok(resp.with_headers(headers))
+    });
+    match resp {
+        Ok(resp) => Some(resp),
+        Err(e) => {
+            log::error!("failed to create response: {e}");
+            None
+        }
+    }
+}
+
+// degraded は組み込みのフォントや単色の背景で代わりに描いた結果で、
+// R2 や外部の画像が戻ればすぐに正しいカードを返せるようにキャッシュしない
+#[derive(Clone)]
+struct Rendered {
+    buffer: Vec<u8>,
+    format: OutputFormat,
+    degraded: bool,
+}
+
+type RenderResult = std::result::Result<Rendered, RenderError>;
+
+// 代わりに描いた結果はブラウザや CDN にも残さない
+const DEGRADED_CACHE_CONTROL: &str = "no-store";
+
+struct MeasureParams {
+    text: String,
+    size: f32,
     max_width: f32,
-    text: &str,
-    target: &mut Vec<Glyph>,
-) where
-    F: Font,
-    SF: ScaleFont<F>,
-{
-    let v_advance = font.height() + font.line_gap();
-    let mut caret = point(position.x, position.y + font.ascent());
-    let mut last_glyph: Option<Glyph> = None;
-    for c in text.chars() {
-        if c.is_control() {
-            if c == '\n' {
-                caret = point(position.x, caret.y + v_advance);
-                last_glyph = None;
+}
+
+// 描画するときの本文と同じ既定値を使う
+fn parse_measure_params(input: &[(String, String)]) -> std::result::Result<MeasureParams, String> {
+    let text = sanitize_text(&query_required(input, "text")?);
+    if text.trim().is_empty() {
+        return Err("text must contain non-whitespace characters".to_string());
+    }
+    if text.len() > 150 {
+        return Err("text parameter is too long".to_string());
+    }
+    let size = query_number(input, "size")?.unwrap_or(TEXT_FONT_SIZE);
+    if !(12.0..=200.0).contains(&size) {
+        return Err("size must be between 12 and 200".to_string());
+    }
+    let max_width = query_number(input, "maxwidth")?
+        .unwrap_or(IMAGE_WIDTH as f32 - DEFAULT_MARGIN_LEFT - DEFAULT_MARGIN_RIGHT);
+    if !(1.0..=MAX_IMAGE_SIZE as f32).contains(&max_width) {
+        return Err(format!("maxwidth must be between 1 and {MAX_IMAGE_SIZE}"));
+    }
+    Ok(MeasureParams {
+        text,
+        size,
+        max_width,
+    })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Measurement {
+    width: f32,
+    height: f32,
+    lines: usize,
+}
+
+fn measure_paragraph<F: Font>(font: &F, params: &MeasureParams) -> Measurement {
+    let font = font.as_scaled(PxScale::from(params.size));
+    let mut glyphs = Vec::new();
+    layout_paragraph(
+        font,
+        point(0.0, 0.0),
+        LayoutOptions::default().with_max_width(params.max_width),
+        &params.text,
+        &mut glyphs,
+    );
+    let bounds = glyph_bounds(font, &glyphs);
+    Measurement {
+        width: bounds.map_or(0.0, |bounds| bounds.width()),
+        height: bounds.map_or(0.0, |bounds| bounds.height()),
+        lines: count_lines(&glyphs),
+    }
+}
+
+async fn measure(
+    env: &Env,
+    params: MeasureParams,
+    theme_font: Option<String>,
+    cache_control: &str,
+    error_format: ErrorFormat,
+) -> Result<Response> {
+    let script_font = match theme_font {
+        Some(_) => None,
+        None => detect_script([params.text.as_str()])
+            .and_then(|script| resolve_script_font(env, script)),
+    };
+    let font_key = theme_font.or(script_font).unwrap_or(FONT_KEY.to_string());
+    let bucket = match font_bucket(env) {
+        Ok(bucket) => bucket,
+        Err(e) => {
+            log::error!("failed to get bucket: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    let fallback_keys = fallback_font_keys(env);
+    let (raw_font, raw_fallbacks) =
+        match fetch_font_data(&bucket, &font_key, &fallback_keys, false).await {
+            Ok(raw_fonts) => raw_fonts,
+            Err(e) => return error_response(e.message, e.status, error_format),
+        };
+    let (fonts, degraded) = match parse_fonts(raw_font, raw_fallbacks) {
+        Ok(fonts) => fonts,
+        Err(e) => return error_response(e.message, e.status, error_format),
+    };
+    let mut headers = Headers::new();
+    if degraded {
+        headers.set("Cache-Control", DEGRADED_CACHE_CONTROL)?;
+    } else {
+        headers.set("Cache-Control", cache_control)?;
+    }
+    Ok(
+        Response::from_json(&measure_paragraph(&fonts.fallback(None), &params))?
+            .with_headers(headers),
+    )
+}
+
+// 先頭のフォントと、取得できた代替フォントの中身
+type FontData = (Option<Vec<u8>>, Vec<(String, Vec<u8>)>);
+
+// 先頭のフォントが取得できなければ None にして、parse_fonts で組み込みのフォントを使う
+// missing_font_image の場合は、見つからないフォントを組み込みのもので描かずにエラーにする
+async fn fetch_font_data(
+    bucket: &Bucket,
+    font_key: &str,
+    fallback_keys: &[String],
+    missing_font_image: bool,
+) -> std::result::Result<FontData, RenderError> {
+    let font_keys: Vec<&str> = [font_key]
+        .into_iter()
+        .chain(fallback_keys.iter().map(String::as_str))
+        .collect();
+    let mut raw_fonts = fetch_fonts(bucket, &font_keys).await;
+    // 描けないよりは組み込みのフォントで描けるところだけでも返す
+    let raw_font = match raw_fonts.remove(0) {
+        Ok(Some(raw_font)) => Some(raw_font),
+        Ok(None) if missing_font_image => {
+            log::error!("font {font_key} is not found");
+            return Err(RenderError::font_not_found());
+        }
+        Ok(None) => {
+            log::warn!("font {font_key} is not found, falling back to the embedded font");
+            None
+        }
+        Err(e) => {
+            log::warn!("failed to get font {font_key}, falling back to the embedded font: {e}");
+            None
+        }
+    };
+    // 代替フォントが使えなくても先頭のフォントで描ける文字は描く
+    let raw_fallbacks = fallback_keys
+        .iter()
+        .zip(raw_fonts)
+        .filter_map(|(key, raw_fallback)| match raw_fallback {
+            Ok(Some(raw_fallback)) => Some((key.clone(), raw_fallback)),
+            Ok(None) => {
+                log::warn!("fallback font {key} is not found, skipping");
+                None
             }
-            continue;
+            Err(e) => {
+                log::warn!("failed to get fallback font {key}, skipping: {e}");
+                None
+            }
+        })
+        .collect();
+    Ok((raw_font, raw_fallbacks))
+}
+
+// 読み込めない代替フォントは飛ばして 1 つの FontCollection にまとめる
+// 先頭のフォントの代わりに組み込みのフォントを使った場合は true も返す
+fn parse_fonts(
+    raw_font: Option<Vec<u8>>,
+    raw_fallbacks: Vec<(String, Vec<u8>)>,
+) -> std::result::Result<(FontCollection, bool), RenderError> {
+    let font = raw_font.and_then(|raw_font| {
+        let signature = font_signature(&raw_font);
+        let len = raw_font.len();
+        FontVec::try_from_vec(raw_font)
+            .map_err(|e| {
+                log::warn!(
+                    "failed to load font, falling back to the embedded font: {e} ({len} bytes, {signature})"
+                );
+            })
+            .ok()
+    });
+    let degraded = font.is_none();
+    let font = match font {
+        Some(font) => font,
+        None => match FontVec::try_from_vec(EMBEDDED_FONT.to_vec()) {
+            Ok(font) => font,
+            Err(e) => {
+                log::error!("failed to load embedded font: {e}");
+                return Err(RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500));
+            }
+        },
+    };
+    let mut fonts = vec![font];
+    for (key, raw_fallback) in raw_fallbacks {
+        let signature = font_signature(&raw_fallback);
+        let len = raw_fallback.len();
+        match FontVec::try_from_vec(raw_fallback) {
+            Ok(fallback) => fonts.push(fallback),
+            Err(e) => log::warn!(
+                "failed to load fallback font {key}, skipping: {e} ({len} bytes, {signature})"
+            ),
+        }
+    }
+    Ok((FontCollection::new(fonts), degraded))
+}
+
+// フォントや背景を取得してカードを描画し、エンコードしたものを返す
+// font_data を渡した場合はフォントを取得し直さずにそれを使う (/batch)
+async fn render_card(
+    buckets: &Buckets,
+    params: Params,
+    font_key: String,
+    fallback_keys: Vec<String>,
+    missing_font_image: bool,
+    font_data: Option<FontData>,
+) -> RenderResult {
+    let template_key = params
+        .template
+        .as_ref()
+        .map(|template| format!("templates/{template}"));
+    let mut field_keys: Vec<&str> = [&params.title_font, &params.text_font, &params.author_font]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    field_keys.sort_unstable();
+    field_keys.dedup();
+    let (raw_fonts, raw_field_fonts, raw_template, bg_image) = futures_util::join!(
+        async {
+            match font_data {
+                Some(font_data) => Ok(font_data),
+                None => {
+                    fetch_font_data(
+                        &buckets.fonts,
+                        &font_key,
+                        &fallback_keys,
+                        missing_font_image,
+                    )
+                    .await
+                }
+            }
+        },
+        fetch_fonts(&buckets.fonts, &field_keys),
+        async {
+            match &template_key {
+                Some(key) => Some(fetch_object(&buckets.assets, key).await),
+                None => None,
+            }
+        },
+        async {
+            match &params.bg_image {
+                Some(url) => Some(fetch_remote_image(url).await),
+                None => None,
+            }
+        }
+    );
+    let (raw_font, raw_fallbacks) = raw_fonts?;
+    let (mut fonts, mut degraded) = parse_fonts(raw_font, raw_fallbacks)?;
+    let mut field_fonts = HashMap::new();
+    for (key, raw_field_font) in field_keys.iter().zip(raw_field_fonts) {
+        match raw_field_font {
+            Ok(Some(raw_field_font)) => {
+                let signature = font_signature(&raw_field_font);
+                let len = raw_field_font.len();
+                let field_font = FontVec::try_from_vec(raw_field_font).map_err(|e| {
+                    log::error!("failed to load font: {e} ({len} bytes, {signature})");
+                    RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500)
+                })?;
+                field_fonts.insert(*key, fonts.push(field_font));
+            }
+            Ok(None) => {
+                log::error!("font {key} is not found");
+                return Err(RenderError::font_not_found());
+            }
+            Err(e) => {
+                log::error!("failed to get font {key}: {e}");
+                return Err(RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500));
+            }
+        }
+    }
+    let field_font = |key: &Option<String>| {
+        key.as_deref()
+            .and_then(|key| field_fonts.get(key))
+            .map(|&index| fonts.fallback(Some(index)))
+    };
+    let title_font = field_font(&params.title_font);
+    let text_font = field_font(&params.text_font);
+    let author_font = field_font(&params.author_font);
+    let font = fonts.fallback(None);
+    let card_fonts = CardFonts {
+        title: title_font.as_ref().unwrap_or(&font),
+        text: text_font.as_ref().unwrap_or(&font),
+        author: author_font.as_ref().unwrap_or(&font),
+    };
+
+    let template = match raw_template {
+        Some(Ok(Some(raw_template))) => match image::load_from_memory(&raw_template) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                log::error!("failed to decode template: {e}");
+                return Err(RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500));
+            }
+        },
+        Some(Ok(None)) => {
+            return Err(RenderError::new("template is not found".to_string(), 404));
+        }
+        Some(Err(e)) => {
+            log::error!("failed to get template: {e}");
+            return Err(RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500));
+        }
+        None => None,
+    };
+    // 外部の画像が使えなくてもカードは単色の背景で返す (キャッシュはしない)
+    let template = match bg_image {
+        Some(Ok(bg_image)) => Some(bg_image),
+        Some(Err(e)) => {
+            log::warn!("failed to fetch bgimage, falling back to solid background: {e}");
+            degraded = true;
+            None
+        }
+        None => template,
+    };
+
+    if params.format == OutputFormat::Svg {
+        return Ok(Rendered {
+            buffer: generate_svg(card_fonts, &params).into_bytes(),
+            format: OutputFormat::Svg,
+            degraded,
+        });
+    }
+    let imgbuf = generate_card_with_fonts(card_fonts, &params, template.as_ref());
+
+    let (buffer, format) = match encode_image(&imgbuf, params.format, &params) {
+        Ok(buffer) => (buffer, params.format),
+        Err(e) if params.format != OutputFormat::Png => {
+            // 指定されたフォーマットでエンコードできなくても PNG で返す
+            log::warn!(
+                "failed to write image as {}, falling back to png: {e}",
+                params.format.name()
+            );
+            match encode_image(&imgbuf, OutputFormat::Png, &params) {
+                Ok(buffer) => (buffer, OutputFormat::Png),
+                Err(e) => {
+                    log::error!("failed to write image: {e}");
+                    return Err(RenderError::new(
+                        format!("failed to encode image as {}", params.format.name()),
+                        500,
+                    ));
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("failed to write image: {e}");
+            return Err(RenderError::new(
+                "failed to encode image as png".to_string(),
+                500,
+            ));
+        }
+    };
+
+    Ok(Rendered {
+        buffer,
+        format,
+        degraded,
+    })
+}
+
+thread_local! {
+    // isolate 内で進行中のレンダリング (キーはキャッシュキー)
+    static IN_FLIGHT: RefCell<HashMap<String, Shared<LocalBoxFuture<'static, RenderResult>>>> =
+        RefCell::new(HashMap::new());
+}
+
+// キャッシュに載る前に同じカードへのリクエストが集中しても 1 回だけ描画する
+// 2 つ目はレンダリングを始めたリクエストかどうかで、キャッシュにはそのリクエストだけが保存する
+async fn single_flight(
+    key: &str,
+    render: impl Future<Output = RenderResult> + 'static,
+) -> (RenderResult, bool) {
+    let (shared, leader) = IN_FLIGHT.with(|in_flight| {
+        let mut in_flight = in_flight.borrow_mut();
+        match in_flight.get(key) {
+            Some(shared) => (shared.clone(), false),
+            None => {
+                let shared = render.boxed_local().shared();
+                in_flight.insert(key.to_string(), shared.clone());
+                (shared, true)
+            }
+        }
+    });
+    let result = shared.clone().await;
+    // 最初に終わったものが取り除く (最初のリクエストが中断されても残らないようにする)
+    IN_FLIGHT.with(|in_flight| {
+        let mut in_flight = in_flight.borrow_mut();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| current.ptr_eq(&shared))
+        {
+            in_flight.remove(key);
+        }
+    });
+    (result, leader)
+}
+
+// theme と mode を input に反映してから variant を決める
+fn resolve_theme_and_variant(
+    env: &Env,
+    input: &mut Vec<(String, String)>,
+) -> std::result::Result<(Theme, Variant), RenderError> {
+    let theme = match query(input, "theme") {
+        Some(theme) => resolve_theme(env, &theme).map_err(|e| RenderError::new(e, 400))?,
+        None => Theme::default(),
+    };
+    let mode = parse_color_mode(input).map_err(|e| RenderError::new(e, 400))?;
+    if let Err(e) = apply_theme(input, &theme, mode) {
+        log::error!("failed to apply theme: {e}");
+        return Err(RenderError::new(INTERNAL_SERVER_ERROR.to_string(), 500));
+    }
+
+    let variant = match query(input, "variant") {
+        Some(variant) => resolve_variant(env, &variant).ok_or(RenderError::new(
+            "variant parameter is invalid".to_string(),
+            400,
+        ))?,
+        None => Variant::default(),
+    };
+    Ok((theme, variant))
+}
+
+fn select_font_key(env: &Env, theme_font: Option<String>, params: &Params) -> String {
+    // テーマでフォントが決まっていなければ文字種から選ぶ
+    let script_font = match theme_font {
+        Some(_) => None,
+        None => detect_script(params.texts()).and_then(|script| resolve_script_font(env, script)),
+    };
+    // 既定のフォントを使う場合はサブセットで足りるならそちらを読み込む
+    match theme_font.or(script_font) {
+        Some(font_key) => font_key,
+        None => resolve_subset_font(env, params.subset_texts()).unwrap_or(FONT_KEY.to_string()),
+    }
+}
+
+// JSON の配列で受け取ったパラメータをまとめて描画し、id.png を並べた ZIP で返す
+// 1 つでも失敗した場合は ZIP を返さずにその id とエラーを返す
+async fn batch(req: &mut Request, env: &Env) -> Result<Response> {
+    if req.method() != Method::Post {
+        return Response::error("Method Not Allowed".to_string(), 405);
+    }
+    // BATCH_SECRET が設定されていなければエンドポイント自体を無効にする
+    let secret = match env.secret("BATCH_SECRET") {
+        Ok(secret) => secret.to_string(),
+        Err(_) => return Response::error("Not Found".to_string(), 404),
+    };
+    if !bearer_authorized(req, &secret) {
+        return Response::error("Unauthorized".to_string(), 401);
+    }
+
+    let body = match req.text().await {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("failed to read request body: {e}");
+            return Response::error("failed to read request body".to_string(), 400);
+        }
+    };
+    if body.len() > MAX_BATCH_BODY_LENGTH {
+        return Response::error("request body is too large".to_string(), 413);
+    }
+    let entries = match batch_entries(&body) {
+        Ok(entries) => entries,
+        Err(e) => return Response::error(e, 400),
+    };
+
+    let hosts = allowed_bgimage_hosts(env);
+    let field_fonts = allowed_field_fonts(env);
+    let fallback_keys = fallback_font_keys(env);
+    let buckets = match Buckets::from_env(env) {
+        Ok(buckets) => buckets,
+        Err(e) => {
+            log::error!("failed to get bucket: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    // 同じフォントのエントリが多いので、フォントはキーごとに 1 回だけ取得する
+    let mut font_data: HashMap<String, FontData> = HashMap::new();
+    let mut files = Vec::new();
+    for (id, mut input) in entries {
+        let (theme, variant) = match resolve_theme_and_variant(env, &mut input) {
+            Ok(resolved) => resolved,
+            Err(e) => return Response::error(format!("{id}: {}", e.message), e.status),
+        };
+        let params = match parse_params(&input, variant) {
+            Ok(params) => params,
+            Err(e) => return Response::error(format!("{id}: {e}"), 400),
+        };
+        // theme や variant でも format を変えられるので、解決したあとでも確かめる
+        if params.format != OutputFormat::Png {
+            return Response::error(
+                format!("{id}: batch entries can only be rendered as png"),
+                400,
+            );
+        }
+        if let Err(e) = check_field_fonts(&params, &field_fonts) {
+            return Response::error(format!("{id}: {e}"), 400);
+        }
+        if let Err(e) = check_debug(&params, env) {
+            return Response::error(format!("{id}: {e}"), 400);
+        }
+        if let Some(bg_image) = &params.bg_image {
+            if !is_allowed_host(bg_image, &hosts) {
+                return Response::error(format!("{id}: bgimage host is not allowed"), 400);
+            }
+        }
+        let font_key = select_font_key(env, theme.font, &params);
+        let data = match font_data.get(&font_key) {
+            Some(data) => data.clone(),
+            None => match fetch_font_data(&buckets.fonts, &font_key, &fallback_keys, false).await {
+                Ok(data) => font_data.entry(font_key.clone()).or_insert(data).clone(),
+                Err(e) => return Response::error(format!("{id}: {}", e.message), e.status),
+            },
+        };
+        let rendered = render_card(
+            &buckets,
+            params,
+            font_key,
+            fallback_keys.clone(),
+            false,
+            Some(data),
+        )
+        .await;
+        match rendered {
+            Ok(rendered) => files.push((format!("{id}.png"), rendered.buffer)),
+            Err(e) => return Response::error(format!("{id}: {}", e.message), e.status),
+        }
+    }
+
+    let mut headers = Headers::new();
+    headers.set("content-type", "application/zip")?;
+    headers.set("Content-Disposition", "attachment; filename=\"cards.zip\"")?;
+    headers.set("Cache-Control", "no-store")?;
+    let archive = match zip::write_zip(&files) {
+        Ok(archive) => archive,
+        Err(e) => {
+            log::error!("failed to write zip: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    Ok(Response::from_bytes(archive)?.with_headers(headers))
+}
+
+// 各エントリの id はファイル名に使うので英数字と - _ に限り、重複も許さない
+// format は ZIP の中身を揃えるため png だけを受け付ける
+type BatchEntry = (String, Vec<(String, String)>);
+
+fn batch_entries(body: &str) -> std::result::Result<Vec<BatchEntry>, String> {
+    let values = match serde_json::from_str(body) {
+        Ok(serde_json::Value::Array(values)) => values,
+        Ok(_) => return Err("request body must be a JSON array".to_string()),
+        Err(_) => return Err("request body is not valid JSON".to_string()),
+    };
+    if values.is_empty() || values.len() > MAX_BATCH_ENTRIES {
+        return Err(format!(
+            "batch must contain between 1 and {MAX_BATCH_ENTRIES} entries"
+        ));
+    }
+    let mut entries: Vec<BatchEntry> = Vec::new();
+    for value in values {
+        let mut object = match value {
+            serde_json::Value::Object(object) => object,
+            _ => return Err("batch entry must be a JSON object".to_string()),
+        };
+        let id = match object.remove("id") {
+            Some(serde_json::Value::String(id))
+                if !id.is_empty()
+                    && id.len() <= 100
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_')) =>
+            {
+                id
+            }
+            _ => return Err("batch entry id is invalid".to_string()),
+        };
+        if entries.iter().any(|(other, _)| *other == id) {
+            return Err(format!("batch entry id {id} is duplicated"));
+        }
+        let input = input_from_object(object).map_err(|e| format!("{id}: {e}"))?;
+        match query(&input, "format").as_deref() {
+            None | Some("png") => {}
+            Some(_) => return Err(format!("{id}: batch entries can only be rendered as png")),
+        }
+        entries.push((id, input));
+    }
+    Ok(entries)
+}
+
+// SECURITY_HEADERS が有効なときにすべてのレスポンスへ付けるヘッダ
+// 画像を直接ブラウザに返すので content-type 以外として解釈させない
+const SECURITY_HEADERS: [(&str, &str); 3] = [
+    ("X-Content-Type-Options", "nosniff"),
+    (
+        "Strict-Transport-Security",
+        "max-age=31536000; includeSubDomains",
+    ),
+    ("Content-Security-Policy", "default-src 'none'"),
+];
+
+// CACHE_MAX_AGE (秒) で画像の max-age を変えられるようにする
+fn cache_max_age(env: &Env) -> u64 {
+    let Ok(value) = env.var("CACHE_MAX_AGE") else {
+        return DEFAULT_CACHE_MAX_AGE;
+    };
+    match value.to_string().trim().parse() {
+        Ok(max_age) => max_age,
+        Err(_) => {
+            log::warn!("CACHE_MAX_AGE is invalid, using the default");
+            DEFAULT_CACHE_MAX_AGE
         }
-        let mut glyph = font.scaled_glyph(c);
-        if let Some(previous) = last_glyph.take() {
-            caret.x += font.kern(previous.id, glyph.id);
+    }
+}
+
+// キャッシュから取り出したレスポンスのヘッダは変更できないので複製してから設定する
+fn with_security_headers(resp: Response) -> Response {
+    let mut headers = resp.headers().clone();
+    for (name, value) in SECURITY_HEADERS {
+        if let Err(e) = headers.set(name, value) {
+            log::error!("failed to set {name} header: {e}");
         }
-        glyph.position = caret;
+    }
+    resp.with_headers(headers)
+}
 
-        last_glyph = Some(glyph.clone());
-        caret.x += font.h_advance(glyph.id);
+#[event(fetch)]
+async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+    let security_headers = env_flag(&env, "SECURITY_HEADERS");
+    let resp = handle(req, env).await?;
+    if security_headers {
+        return Ok(with_security_headers(resp));
+    }
+    Ok(resp)
+}
 
-        if !c.is_whitespace() && caret.x > position.x + max_width {
-            caret = point(position.x, caret.y + v_advance);
-            last_glyph = None;
+async fn handle(mut req: Request, env: Env) -> Result<Response> {
+    let url = match req.url() {
+        Ok(url) => url,
+        _ => {
+            log::error!("failed to get url");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
         }
+    };
+    // パースやレンダリングの前に極端に長いリクエストを弾く
+    if url.query().map_or(0, str::len) > MAX_QUERY_LENGTH {
+        return Response::error("query string is too long".to_string(), 414);
+    }
+    if url.path() == "/purge" {
+        return purge(&req, &env, &url).await;
+    }
+    if url.path() == "/batch" {
+        return batch(&mut req, &env).await;
+    }
+    if url.path() == "/font" {
+        return serve_font(&req, &env, &url).await;
+    }
+
+    // パラメータを読む前のエラーは Accept だけで形式を決める
+    let accept_error_format = negotiate_error_format(
+        &req.headers()
+            .get("Accept")
+            .ok()
+            .flatten()
+            .unwrap_or_default(),
+    );
+    let input = match req.method() {
+        Method::Get => match input_from_url(&url) {
+            Ok(input) => input,
+            Err(e) => return error_response(e, 400, accept_error_format),
+        },
+        Method::Post => {
+            // Content-Length があれば読み込む前に判定する
+            if let Ok(Some(length)) = req.headers().get("Content-Length") {
+                if length
+                    .parse::<usize>()
+                    .is_ok_and(|length| length > MAX_BODY_LENGTH)
+                {
+                    let message = "request body is too large".to_string();
+                    return error_response(message, 413, accept_error_format);
+                }
+            }
+            let body = match req.text().await {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("failed to read request body: {e}");
+                    let message = "failed to read request body".to_string();
+                    return error_response(message, 400, accept_error_format);
+                }
+            };
+            if body.len() > MAX_BODY_LENGTH {
+                let message = "request body is too large".to_string();
+                return error_response(message, 413, accept_error_format);
+            }
+            match input_from_json(&body) {
+                Ok(input) => input,
+                Err(e) => return error_response(e, 400, accept_error_format),
+            }
+        }
+        _ => {
+            return Response::error("Method Not Allowed".to_string(), 405);
+        }
+    };
+    // DISABLE_CACHE は開発環境向けにすべてのリクエストでキャッシュを使わない
+    let cache_disabled = env_flag(&env, "DISABLE_CACHE");
+    let max_age = cache_max_age(&env);
+    let cache_control = if cache_disabled {
+        "no-store".to_string()
+    } else {
+        format!("public, max-age={max_age}")
+    };
+    let cache_control = cache_control.as_str();
+
+    // auto はリクエストヘッダーから決めた値に置き換えてキャッシュキーにも反映する
+    // quality 付きの auto は Accept に関わらず PNG になる (Vary: Accept でも同じものを返す)
+    let mut input = input;
+    let mut vary = Vec::new();
+    if query(&input, "format").as_deref() == Some("auto") {
+        let accept = req
+            .headers()
+            .get("Accept")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        // quality は WebP と組み合わせられないので、指定されていれば PNG にする
+        // (lossy WebP の encoder がないので quality は JPEG だけで使う)
+        let format = match negotiate_format(&accept) {
+            OutputFormat::WebP if query(&input, "quality").is_some() => OutputFormat::Png,
+            format => format,
+        };
+        for (_, value) in input.iter_mut().filter(|(k, _)| k == "format") {
+            *value = format.name().to_string();
+        }
+        vary.push("Accept");
+    }
+    // Cache API は GET しか扱えないので POST もパラメータからキーを作る
+    let cache_url = cache_key(&url, &input, &ignored_cache_params(&env));
+    // クローラーにテキストを返すとプレビューが壊れた画像になるのでエラーを画像で返す
+    let crawler = req
+        .headers()
+        .get("User-Agent")
+        .ok()
+        .flatten()
+        .is_some_and(|user_agent| is_crawler(&user_agent));
+    let error_format = if crawler && error_cards_enabled(&env, &input) {
+        ErrorFormat::Image
+    } else {
+        accept_error_format
+    };
+
+    let cache = Cache::default();
+    let cache_key = CacheKey::Url(cache_url.clone());
+
+    let cached = if cache_disabled {
+        None
+    } else {
+        match cache.get(cache_key, false).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                log::error!("failed to get cache: {e}");
+                None
+            }
+        }
+    };
+    match cached {
+        // テキストの 400 があれば、ほかの形式の 400 は形式ごとのキーから返す
+        Some(cached) if cached.status_code() == 400 && error_format != ErrorFormat::Text => {
+            match cache
+                .get(error_cache_key(&cache_url, error_format), false)
+                .await
+            {
+                Ok(Some(cached)) => return Ok(with_cache_status(cached, "HIT")),
+                Ok(None) => {
+                    log::info!("cached error for {error_format:?} not found (url = {cache_url})");
+                }
+                Err(e) => {
+                    log::error!("failed to get cache: {e}");
+                }
+            }
+        }
+        Some(cached) => {
+            return Ok(with_cache_status(cached, "HIT"));
+        }
+        None => {
+            log::info!("cache not found (url = {cache_url})");
+        }
+    }
+
+    // format=meta は画像の代わりに、同じパラメータの画像を指す meta タグを返す
+    let meta_image_url = (query(&input, "format").as_deref() == Some("meta")).then(|| {
+        input.retain(|(k, _)| k != "format");
+        image_url(&url, &input)
+    });
+
+    let (theme, variant) = match resolve_theme_and_variant(&env, &mut input) {
+        Ok(resolved) => resolved,
+        Err(e) if e.status == 400 => {
+            return bad_request(&cache, &cache_url, e.message, cache_disabled, error_format).await;
+        }
+        Err(e) => return error_response(e.message, e.status, error_format),
+    };
+    // measure=1 は画像を作らずに本文を折り返した大きさだけを返す
+    if query_flag(&input, "measure") {
+        let params = match parse_measure_params(&input) {
+            Ok(params) => params,
+            Err(e) => {
+                return bad_request(&cache, &cache_url, e, cache_disabled, error_format).await;
+            }
+        };
+        return measure(&env, params, theme.font, cache_control, error_format).await;
+    }
+    let params = match parse_params(&input, variant) {
+        Ok(params) => params,
+        Err(e) => {
+            return bad_request(&cache, &cache_url, e, cache_disabled, error_format).await;
+        }
+    };
+    if let Some(image_url) = meta_image_url {
+        let mut headers = Headers::new();
+        headers.set("content-type", "text/html; charset=utf-8")?;
+        headers.set("Cache-Control", cache_control)?;
+        return Ok(Response::ok(meta_tags(&params, &image_url))?.with_headers(headers));
+    }
+    // /alt は同じパラメータから og:image:alt 用の説明文を返す
+    if url.path() == "/alt" {
+        let mut headers = Headers::new();
+        headers.set("content-type", "text/plain; charset=utf-8")?;
+        headers.set("Cache-Control", cache_control)?;
+        return Ok(Response::ok(params.alt_text())?.with_headers(headers));
+    }
+
+    let font_key = select_font_key(&env, theme.font, &params);
+    let checked = check_field_fonts(&params, &allowed_field_fonts(&env))
+        .and_then(|_| check_debug(&params, &env))
+        .and_then(|_| match &params.bg_image {
+            Some(bg_image) if !is_allowed_host(bg_image, &allowed_bgimage_hosts(&env)) => {
+                Err("bgimage host is not allowed".to_string())
+            }
+            _ => Ok(()),
+        });
+    if let Err(e) = checked {
+        return bad_request(&cache, &cache_url, e, cache_disabled, error_format).await;
+    }
+
+    // 同じパラメータなら同じ画像になるので、再検証はフォントの読み込みや描画をせずに返す
+    // 通る値だと分かってから確かめ、キャッシュを使わない開発環境では毎回描画する
+    let etag = params_etag(&cache_url);
+    let revalidated = !cache_disabled
+        && req
+            .headers()
+            .get("If-None-Match")
+            .ok()
+            .flatten()
+            .is_some_and(|value| etag_matches(&value, &etag));
+    if revalidated {
+        let mut headers = Headers::new();
+        headers.set("ETag", &etag)?;
+        headers.set("Cache-Control", cache_control)?;
+        if !vary.is_empty() {
+            headers.set("Vary", &vary.join(", "))?;
+        }
+        return Ok(Response::empty()?.with_status(304).with_headers(headers));
+    }
+
+    let buckets = match Buckets::from_env(&env) {
+        Ok(buckets) => buckets,
+        Err(e) => {
+            log::error!("failed to get bucket: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    let render_params = params.clone();
+    let fallback_keys = fallback_font_keys(&env);
+    let missing_font_error = env_flag(&env, "MISSING_FONT_IMAGE");
+    let (rendered, leader) = single_flight(&cache_url, async move {
+        render_card(
+            &buckets,
+            render_params,
+            font_key,
+            fallback_keys,
+            missing_font_error,
+            None,
+        )
+        .await
+    })
+    .await;
+    let rendered = match rendered {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            if e.is_font_not_found() {
+                if let Some(resp) = missing_font_image(&env).await {
+                    return Ok(resp);
+                }
+            }
+            return error_response(e.message, e.status, error_format);
+        }
+    };
+
+    let resp = match Response::from_bytes(rendered.buffer) {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::error!("failed to create response: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    let cache_control = if rendered.degraded {
+        DEGRADED_CACHE_CONTROL
+    } else {
+        cache_control
+    };
+    let mut headers = Headers::new();
+    match headers.set("content-type", rendered.format.content_type()) {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("failed to set content-type header: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    match headers.set("Cache-Control", cache_control) {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("failed to set Cache-Control header: {e}");
+            return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+        }
+    };
+    // 代わりに描いたカードを 304 で使い続けられないように ETag は付けない
+    if !rendered.degraded {
+        match headers.set("ETag", &etag) {
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("failed to set ETag header: {e}");
+                return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+            }
+        };
+    }
+    if !vary.is_empty() {
+        match headers.set("Vary", &vary.join(", ")) {
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("failed to set Vary header: {e}");
+                return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+            }
+        };
+    }
+    if params.noindex {
+        match headers.set("X-Robots-Tag", "noindex") {
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("failed to set X-Robots-Tag header: {e}");
+                return Response::error(INTERNAL_SERVER_ERROR.to_string(), 500);
+            }
+        };
+    }
+    let mut resp = resp.with_headers(headers);
+    if cache_disabled || rendered.degraded {
+        return Ok(with_cache_status(resp, "BYPASS"));
+    }
+    // 同じカードを待っていたリクエストは結果を返すだけで、保存は最初のリクエストに任せる
+    if !leader {
+        return Ok(with_cache_status(resp, "MISS"));
+    }
+    put_cache(&cache, &cache_url, &mut resp).await;
+
+    // キャッシュに保存したあとで付けるので HIT のレスポンスに MISS が残ることはない
+    Ok(with_cache_status(resp, "MISS"))
+}
+
+// 決まった 400 は形式ごとのキーで短くキャッシュに入れ、次からは cache.get の時点で返す
+// テキストは画像と同じキーに入れ、ほかの形式の 400 を探す目印にもする
+async fn bad_request(
+    cache: &Cache,
+    cache_url: &str,
+    message: String,
+    cache_disabled: bool,
+    format: ErrorFormat,
+) -> Result<Response> {
+    // 画像は描けているので、パラメータの誤りは 422 で返す
+    let status = if format == ErrorFormat::Image {
+        422
+    } else {
+        400
+    };
+    if cache_disabled {
+        let resp = error_response_with(message, status, format, "no-store")?;
+        return Ok(with_cache_status(resp, "BYPASS"));
+    }
+    let mut resp = error_response_with(message.clone(), status, format, BAD_REQUEST_CACHE_CONTROL)?;
+    put_cache(cache, &error_cache_key(cache_url, format), &mut resp).await;
+    if format != ErrorFormat::Text {
+        let mut text =
+            error_response_with(message, 400, ErrorFormat::Text, BAD_REQUEST_CACHE_CONTROL)?;
+        put_cache(cache, cache_url, &mut text).await;
+    }
+    Ok(with_cache_status(resp, "MISS"))
+}
+
+// テキスト以外の 400 は形式を足したキーに入れる
+fn error_cache_key(cache_url: &str, format: ErrorFormat) -> String {
+    let name = match format {
+        ErrorFormat::Text => return cache_url.to_string(),
+        ErrorFormat::Json => "json",
+        ErrorFormat::Image => "png",
+    };
+    let separator = if cache_url.contains('?') { '&' } else { '?' };
+    format!("{cache_url}{separator}errorformat={name}")
+}
+
+// cache に保存できなくてもレスポンスは返す
+async fn put_cache(cache: &Cache, key: &str, resp: &mut Response) {
+    match resp.cloned() {
+        Ok(cloned_resp) => {
+            if let Err(e) = cache.put(key, cloned_resp).await {
+                log::error!("failed to put cache: {e}");
+            }
+        }
+        Err(e) => {
+            log::error!("failed to clone response, skipping cache: {e}");
+        }
+    }
+}
+
+// 求められた形式でエラーを返す (キャッシュはしない)
+fn error_response(message: String, status: u16, format: ErrorFormat) -> Result<Response> {
+    error_response_with(message, status, format, "no-store")
+}
+
+fn error_response_with(
+    message: String,
+    status: u16,
+    format: ErrorFormat,
+    cache_control: &str,
+) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Cache-Control", cache_control)?;
+    let resp = match format {
+        ErrorFormat::Image => return error_card(&message, status, cache_control),
+        ErrorFormat::Json => Response::from_json(&serde_json::json!({ "error": message }))?,
+        ErrorFormat::Text => {
+            headers.set("content-type", "text/plain; charset=utf-8")?;
+            Response::ok(message)?
+        }
+    };
+    Ok(resp.with_status(status).with_headers(headers))
+}
+
+// キャッシュから返したかどうかをクライアントからも確かめられるようにする
+// (キャッシュから取り出したレスポンスのヘッダは変更できないので複製してから設定する)
+fn with_cache_status(resp: Response, status: &str) -> Response {
+    let mut headers = resp.headers().clone();
+    if let Err(e) = headers.set("X-Cache", status) {
+        log::error!("failed to set X-Cache header: {e}");
+    }
+    resp.with_headers(headers)
+}
+
+#[derive(Clone)]
+struct Params {
+    width: u32,
+    height: u32,
+    format: OutputFormat,
+    quality: u8,
+    title: String,
+    title_max_lines: usize,
+    title_min_size: f32,
+    title_fit: TitleFit,
+    highlight: Option<String>,
+    highlight_color: (u8, u8, u8),
+    text_gradient: Option<Gradient>,
+    text: String,
+    columns: Option<(String, String)>,
+    max_lines: Option<usize>,
+    overflow: Overflow,
+    items: Vec<String>,
+    list_style: ListStyle,
+    author: Option<String>,
+    stat: Option<String>,
+    stat_label: Option<String>,
+    site_name: Option<String>,
+    background: (u8, u8, u8),
+    title_color: (u8, u8, u8),
+    text_color: (u8, u8, u8),
+    author_color: (u8, u8, u8),
+    author_pill: Option<(u8, u8, u8)>,
+    title_font: Option<String>,
+    text_font: Option<String>,
+    author_font: Option<String>,
+    footer: Option<Footer>,
+    margin_left: f32,
+    margin_right: f32,
+    // 文字のまとまり全体をずらす量 (offsetx, offsety)
+    offset: Point,
+    tracking: Length,
+    tabular: bool,
+    leading: Length,
+    anchor: Anchor,
+    align: Align,
+    position: VerticalPosition,
+    badge: Option<String>,
+    category: Option<Category>,
+    watermark: Option<String>,
+    rule: Option<Rule>,
+    accent: Option<Accent>,
+    shadow: Option<Shadow>,
+    stroke: Option<Stroke>,
+    panel: Option<Panel>,
+    title_panel: Option<Panel>,
+    template: Option<String>,
+    bg_image: Option<Url>,
+    blur: f32,
+    filter: Option<Filter>,
+    flip_horizontal: bool,
+    radius: f32,
+    dpi: Option<u32>,
+    scale: f32,
+    notdef: Notdef,
+    subsampling: Subsampling,
+    strip_alpha: bool,
+    gamma: bool,
+    antialias: bool,
+    embed_meta: bool,
+    noindex: bool,
+    debug_bounds: bool,
+}
+
+impl Params {
+    fn paint(&self, color: (u8, u8, u8)) -> TextPaint {
+        TextPaint {
+            color,
+            gamma: self.gamma,
+            antialias: self.antialias,
+            scale: self.scale,
+            shadow: self.shadow,
+            stroke: self.stroke,
+            gradient: None,
+        }
+    }
+
+    // textgradient は title と本文にだけ使う
+    fn text_paint(&self, color: (u8, u8, u8)) -> TextPaint {
+        TextPaint {
+            gradient: self.text_gradient,
+            ..self.paint(color)
+        }
+    }
+
+    // 描画とエンコードにかかる時間の目安 (キャンバスのピクセル数に処理ごとの倍率を掛ける)
+    fn render_cost(&self) -> f32 {
+        let (width, height) = self.canvas_size();
+        let encode = match self.format {
+            OutputFormat::Png | OutputFormat::Jpeg => 1.0,
+            // 可逆圧縮しかないので PNG より重い
+            OutputFormat::WebP => 3.0,
+            // ラスタライズしないのでパスの書き出しだけで済む
+            OutputFormat::Svg => 0.5,
+        };
+        // ぼかしはカーネルの半径に比例して重くなる
+        let blur = match (&self.template, &self.bg_image) {
+            (None, None) => 0.0,
+            _ => self.blur * self.scale / 2.0,
+        };
+        let shadow = self
+            .shadow
+            .map_or(0.0, |shadow| shadow.blur * self.scale / 2.0);
+        // 線は太さの 2 乗の範囲を調べる
+        let stroke = self
+            .stroke
+            .map_or(0.0, |stroke| (stroke.width * self.scale / 2.0).powi(2));
+        // 透かしはすべてのピクセルで補間する
+        let watermark = if self.watermark.is_some() { 1.0 } else { 0.0 };
+        (width * height) as f32 * (encode + blur + shadow + stroke + watermark)
+    }
+
+    // レイアウトは width と height の座標で行い、描画するときに scale 倍する
+    fn canvas_size(&self) -> (u32, u32) {
+        (
+            (self.width as f32 * self.scale).round() as u32,
+            (self.height as f32 * self.scale).round() as u32,
+        )
+    }
+
+    fn canvas_rect(&self, rect: Rect) -> Rect {
+        Rect {
+            min: point(rect.min.x * self.scale, rect.min.y * self.scale),
+            max: point(rect.max.x * self.scale, rect.max.y * self.scale),
+        }
+    }
+
+    fn description(&self) -> String {
+        match &self.columns {
+            Some((left, right)) => format!("{left} / {right}"),
+            None if self.items.is_empty() => self.text.clone(),
+            None => self.items.join(", "),
+        }
+    }
+
+    fn alt_text(&self) -> String {
+        let mut alt = format!("{} — {}", self.title, self.description());
+        if let Some(author) = &self.author {
+            alt.push_str(&format!(" by {author}"));
+        }
+        alt
+    }
+
+    fn texts(&self) -> impl Iterator<Item = &str> {
+        [Some(&self.title), Some(&self.text), self.author.as_ref()]
+            .into_iter()
+            .flatten()
+            .chain(&self.items)
+            .chain(self.columns.iter().flat_map(|(left, right)| [left, right]))
+            .map(String::as_str)
+    }
+
+    // サブセットのフォントで描けるか調べるため、リストのマーカーや省略記号も含めて描画する文字列を返す
+    fn subset_texts(&self) -> Vec<&str> {
+        let mut texts: Vec<&str> = self
+            .texts()
+            .chain(
+                [
+                    &self.stat,
+                    &self.stat_label,
+                    &self.site_name,
+                    &self.badge,
+                    &self.watermark,
+                ]
+                .into_iter()
+                .flatten()
+                .chain(self.category.iter().map(|category| &category.text))
+                .map(String::as_str),
+            )
+            .collect();
+        if !self.items.is_empty() {
+            texts.push(match self.list_style {
+                ListStyle::Bullet => "•",
+                ListStyle::Number => "0123456789.",
+            });
+        }
+        if self.max_lines.is_some() && self.overflow == Overflow::Ellipsis {
+            texts.push("…");
+        }
+        texts
+    }
+
+    // 描画する文字列からフォントにない文字を取り除いたものを返す
+    fn without_missing_glyphs<F: Font>(&self, fonts: CardFonts<'_, F>) -> Params {
+        let strip_with = |font: &F, text: &str| -> String {
+            text.chars()
+                .filter(|&c| c.is_control() || font.glyph_id(c).0 != 0)
+                .collect()
+        };
+        let strip = |text: &str| strip_with(fonts.text, text);
+        let mut params = self.clone();
+        params.title = strip_with(fonts.title, &params.title);
+        params.text = strip(&params.text);
+        params.items = params.items.iter().map(|item| strip(item)).collect();
+        params.columns = params
+            .columns
+            .as_ref()
+            .map(|(left, right)| (strip(left), strip(right)));
+        params.author = params
+            .author
+            .as_deref()
+            .map(|author| strip_with(fonts.author, author));
+        params.stat = params.stat.as_deref().map(strip);
+        params.site_name = params
+            .site_name
+            .as_deref()
+            .map(|site_name| strip_with(fonts.author, site_name));
+        params.stat_label = params.stat_label.as_deref().map(strip);
+        params.badge = params.badge.as_deref().map(strip);
+        params.watermark = params.watermark.as_deref().map(strip);
+        if let Some(category) = &mut params.category {
+            category.text = strip(&category.text);
+        }
+        params
+    }
+}
+
+// 部分ごとに描くフォント (titlefont などがなければすべて同じフォントを使う)
+struct CardFonts<'a, F> {
+    title: &'a F,
+    text: &'a F,
+    author: &'a F,
+}
+
+// F が Clone でなくても参照だけを複製できるようにする
+impl<F> Clone for CardFonts<'_, F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F> Copy for CardFonts<'_, F> {}
+
+impl<'a, F> CardFonts<'a, F> {
+    fn uniform(font: &'a F) -> Self {
+        CardFonts {
+            title: font,
+            text: font,
+            author: font,
+        }
+    }
+}
+
+// フォントに含まれない文字 (.notdef) の扱い
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Notdef {
+    Skip,
+    Box,
+}
+
+impl Notdef {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "skip" => Some(Notdef::Skip),
+            "box" => Some(Notdef::Box),
+            _ => None,
+        }
+    }
+}
+
+// maxlines を超えた本文の扱い
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Overflow {
+    Ellipsis,
+    Shrink,
+    Clip,
+}
+
+impl Overflow {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "ellipsis" => Some(Overflow::Ellipsis),
+            "shrink" => Some(Overflow::Shrink),
+            "clip" => Some(Overflow::Clip),
+            _ => None,
+        }
+    }
+}
+
+// title の大きさの決め方
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TitleFit {
+    // titlemaxlines 行に収まるまで小さくする
+    Lines,
+    // 折り返さずに max_width ちょうどの幅になるまで拡大縮小する
+    // titlemaxlines は使わず、titleminsize まで小さくしても収まらなければ行数に関わらず折り返す
+    Width,
+}
+
+impl TitleFit {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "lines" => Some(TitleFit::Lines),
+            "width" => Some(TitleFit::Width),
+            _ => None,
+        }
+    }
+}
+
+// 下端に敷く帯
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Footer {
+    height: f32,
+    color: (u8, u8, u8),
+}
+
+// 背景とのコントラスト比 (WCAG) が大きい方の黒か白を返す
+fn contrast_color(background: (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = background;
+    let luminance =
+        0.2126 * srgb_to_linear(r) + 0.7152 * srgb_to_linear(g) + 0.0722 * srgb_to_linear(b);
+    if (luminance + 0.05) / 0.05 >= 1.05 / (luminance + 0.05) {
+        (0, 0, 0)
+    } else {
+        (255, 255, 255)
+    }
+}
+
+// 本文や title の後ろに敷く半透明の角丸矩形
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Panel {
+    color: (u8, u8, u8),
+    opacity: f32,
+}
+
+// rrggbbaa で指定する
+fn query_panel(
+    input: &[(String, String)],
+    name: &str,
+) -> std::result::Result<Option<Panel>, String> {
+    match query(input, name) {
+        Some(panel) => {
+            let (color, alpha) =
+                parse_color_alpha(&panel).ok_or(format!("{name} parameter is invalid"))?;
+            Ok(Some(Panel {
+                color,
+                opacity: alpha as f32 / 255.0,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Category {
+    text: String,
+    color: (u8, u8, u8),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Rule {
+    thickness: f32,
+    color: (u8, u8, u8),
+}
+
+// title の上か左に並べる短い飾りの棒
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Accent {
+    color: (u8, u8, u8),
+    count: usize,
+    position: AccentPosition,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum AccentPosition {
+    // title の上に横向きの棒を右へ並べる
+    Top,
+    // title の左に縦向きの棒を左へ並べる
+    Left,
+}
+
+impl AccentPosition {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "top" => Some(AccentPosition::Top),
+            "left" => Some(AccentPosition::Left),
+            _ => None,
+        }
+    }
+}
+
+// 文字の下にずらしてぼかした影を描く
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Shadow {
+    offset: Point,
+    blur: f32,
+    color: (u8, u8, u8),
+}
+
+// 文字の範囲の左端から右端にかけて from から to に変える
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Gradient {
+    from: (u8, u8, u8),
+    to: (u8, u8, u8),
+}
+
+impl Gradient {
+    fn at(&self, t: f32) -> (u8, u8, u8) {
+        let t = t.clamp(0.0, 1.0);
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+        (
+            mix(self.from.0, self.to.0),
+            mix(self.from.1, self.to.1),
+            mix(self.from.2, self.to.2),
+        )
+    }
+}
+
+// 文字の輪郭に沿って線を引く (hollow なら中は塗らない)
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Stroke {
+    width: f32,
+    // 指定がなければ文字の色で引く
+    color: Option<(u8, u8, u8)>,
+    hollow: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TextPaint {
+    color: (u8, u8, u8),
+    gamma: bool,
+    antialias: bool,
+    scale: f32,
+    shadow: Option<Shadow>,
+    stroke: Option<Stroke>,
+    // あれば color の代わりに使う
+    gradient: Option<Gradient>,
+}
+
+impl TextPaint {
+    fn coverage(&self, v: f32) -> f32 {
+        if self.antialias {
+            v
+        } else if v >= 0.5 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// 表示されないのに並びや見た目を変えてしまう文字 (なりすましに使える)
+// ab_glyph は shaping をしないので結合子も描画には影響しない
+fn is_unsafe_char(c: char) -> bool {
+    matches!(c,
+        // bidi の埋め込み・上書き・分離
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+        // ゼロ幅の文字と BOM
+        | '\u{200B}'..='\u{200D}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}'
+        // タグ文字
+        | '\u{E0000}'..='\u{E007F}'
+        // 非文字
+        | '\u{FDD0}'..='\u{FDEF}')
+        || (c as u32 & 0xFFFE) == 0xFFFE
+}
+
+fn sanitize_text(text: &str) -> String {
+    text.chars().filter(|&c| !is_unsafe_char(c)).collect()
+}
+
+// normalize=nfkc で全角英数字や半角カナなどを揃える、描画する文字列のパラメータ
+const NORMALIZED_PARAMS: &[&str] = &[
+    "title",
+    "text",
+    "item",
+    "md",
+    "texttemplate",
+    "left",
+    "right",
+    "author",
+    "authorprefix",
+    "badge",
+    "category",
+    "sitename",
+    "stat",
+    "statlabel",
+    "highlight",
+    "watermark",
+];
+
+fn sanitize_input(input: &[(String, String)], normalize: bool) -> Vec<(String, String)> {
+    input
+        .iter()
+        .map(|(k, v)| {
+            if normalize && NORMALIZED_PARAMS.contains(&k.as_str()) {
+                (k.clone(), sanitize_text(&v.nfkc().collect::<String>()))
+            } else {
+                (k.clone(), sanitize_text(v))
+            }
+        })
+        .collect()
+}
+
+fn parse_params(
+    input: &[(String, String)],
+    variant: Variant,
+) -> std::result::Result<Params, String> {
+    // 入力そのままの形を残したい場合もあるので正規化は指定されたときだけ行う
+    let normalize = parse_enum_param(input, "normalize", |s| (s == "nfkc").then_some(true), false)?;
+    // 文字数の制限や空文字列の判定は取り除いたあとの値で行う
+    let input = &sanitize_input(input, normalize);
+    // item が指定された場合は text の代わりに箇条書きで描画する
+    let items: Vec<String> = input
+        .iter()
+        .filter(|(k, _)| k == "item")
+        .map(|(_, v)| v.to_string())
+        .collect();
+    let markdown = match query(input, "md") {
+        Some(md) => {
+            if query(input, "text").is_some() || !items.is_empty() {
+                return Err("md parameter cannot be combined with text or item".to_string());
+            }
+            Some(split_markdown(&md))
+        }
+        None => None,
+    };
+    // template は背景画像の名前に使っているので texttemplate とする
+    let text_template = match query(input, "texttemplate") {
+        Some(template) => {
+            if query(input, "text").is_some() || !items.is_empty() || markdown.is_some() {
+                return Err(
+                    "texttemplate parameter cannot be combined with text, item or md".to_string(),
+                );
+            }
+            Some(fill_template(&template, input)?)
+        }
+        None => None,
+    };
+    // left と right は本文を 2 段に分けて並べる
+    let columns = match (query(input, "left"), query(input, "right")) {
+        (Some(left), Some(right)) => {
+            if query(input, "text").is_some()
+                || !items.is_empty()
+                || markdown.is_some()
+                || text_template.is_some()
+            {
+                return Err(
+                    "left and right parameters cannot be combined with text, item, md or texttemplate"
+                        .to_string(),
+                );
+            }
+            if left.trim().is_empty() || right.trim().is_empty() {
+                return Err("left and right must contain non-whitespace characters".to_string());
+            }
+            if left.len() > 150 || right.len() > 150 {
+                return Err("left or right parameter is too long".to_string());
+            }
+            Some((left, right))
+        }
+        (None, None) => None,
+        _ => return Err("left and right parameters must be given together".to_string()),
+    };
+    let text = if columns.is_some() {
+        String::new()
+    } else if items.is_empty() {
+        let text = match (&markdown, text_template) {
+            (Some((_, body)), _) if body.trim().is_empty() => {
+                return Err("md must contain a paragraph".to_string());
+            }
+            (Some((_, body)), _) => body.clone(),
+            (None, Some(text)) if text.trim().is_empty() => {
+                return Err("texttemplate must produce non-whitespace characters".to_string());
+            }
+            (None, Some(text)) => text,
+            (None, None) => query_required(input, "text")?,
+        };
+        if text.len() > 150 {
+            return Err("text parameter is too long".to_string());
+        }
+        text
+    } else {
+        if query(input, "text").is_some() {
+            return Err("text and item parameters cannot be combined".to_string());
+        }
+        if items.len() > MAX_ITEMS {
+            return Err(format!(
+                "item parameter can be given at most {MAX_ITEMS} times"
+            ));
+        }
+        if items.iter().any(|item| item.trim().is_empty()) {
+            return Err("item must contain non-whitespace characters".to_string());
+        }
+        if items.iter().any(|item| item.len() > 150) {
+            return Err("item parameter is too long".to_string());
+        }
+        String::new()
+    };
+    let title_max_lines =
+        query_u8(input, "titlemaxlines", 1, 5)?.map_or(DEFAULT_TITLE_MAX_LINES, usize::from);
+    let title_min_size = query_number(input, "titleminsize")?.unwrap_or(DEFAULT_TITLE_MIN_SIZE);
+    if !(12.0..=TITLE_FONT_SIZE).contains(&title_min_size) {
+        return Err(format!(
+            "titleminsize must be between 12 and {TITLE_FONT_SIZE}"
+        ));
+    }
+    let title_fit = parse_enum_param(input, "fit", TitleFit::from_param, TitleFit::Lines)?;
+
+    let max_lines = query_u8(input, "maxlines", 1, 10)?.map(usize::from);
+    if max_lines.is_some() && !items.is_empty() {
+        return Err("maxlines cannot be combined with item parameters".to_string());
+    }
+    if query(input, "overflow").is_some() && max_lines.is_none() {
+        return Err("overflow parameter requires maxlines".to_string());
+    }
+    let overflow = parse_enum_param(input, "overflow", Overflow::from_param, Overflow::Ellipsis)?;
+
+    let list_style = parse_enum_param(input, "list", ListStyle::from_param, ListStyle::Bullet)?;
+    // author は省略でき、空白だけの場合も省略したものとして扱う
+    let author = query(input, "author")
+        .filter(|author| !author.trim().is_empty())
+        // prefix も含めてレイアウトする
+        .map(|author| match query(input, "authorprefix") {
+            Some(prefix) => format!("{prefix}{author}"),
+            None => author,
+        });
+    let title = match markdown.and_then(|(heading, _)| heading) {
+        Some(heading) => {
+            if query(input, "title").is_some() {
+                return Err("md heading cannot be combined with title parameter".to_string());
+            }
+            heading
+        }
+        None => query_required(input, "title")?,
+    };
+
+    // author と同じ行の右端に描くので、左下に置く stat とは同時に使えない
+    let site_name = query(input, "sitename").filter(|site_name| !site_name.trim().is_empty());
+    if site_name
+        .as_ref()
+        .is_some_and(|site_name| site_name.chars().count() > 40)
+    {
+        return Err("sitename parameter is too long".to_string());
+    }
+    if site_name.is_some() && query(input, "stat").is_some() {
+        return Err("sitename and stat parameters cannot be combined".to_string());
+    }
+
+    // stat は大きな数値などを、statlabel はその横に小さく添える説明を表す
+    let stat = query(input, "stat").filter(|stat| !stat.trim().is_empty());
+    if stat.as_ref().is_some_and(|stat| stat.chars().count() > 20) {
+        return Err("stat parameter is too long".to_string());
+    }
+    let stat_label = match query(input, "statlabel") {
+        Some(_) if stat.is_none() => {
+            return Err("statlabel parameter requires stat".to_string());
+        }
+        Some(label) if label.chars().count() > 30 => {
+            return Err("statlabel parameter is too long".to_string());
+        }
+        label => label,
+    };
+
+    let format = parse_enum_param(
+        input,
+        "format",
+        OutputFormat::from_param,
+        variant.format.unwrap_or(OutputFormat::Png),
+    )?;
+    // ratio は長辺を IMAGE_WIDTH にしたよく使う縦横比の大きさを選ぶ
+    let ratio_size = match query(input, "ratio") {
+        Some(_) if query(input, "width").is_some() || query(input, "height").is_some() => {
+            return Err("ratio cannot be combined with width or height".to_string());
+        }
+        Some(ratio) => match ratio.as_str() {
+            "1.91" => Some((IMAGE_WIDTH, IMAGE_HEIGHT)),
+            "1" => Some((IMAGE_WIDTH, IMAGE_WIDTH)),
+            "2" => Some((IMAGE_WIDTH, IMAGE_WIDTH / 2)),
+            _ => return Err("ratio must be one of 1.91, 1 or 2".to_string()),
+        },
+        None => None,
+    };
+    let width = query_number(input, "width")?
+        .or(ratio_size.map(|(width, _)| width))
+        .or(variant.width)
+        .unwrap_or(IMAGE_WIDTH);
+    let height = query_number(input, "height")?
+        .or(ratio_size.map(|(_, height)| height))
+        .or(variant.height)
+        .unwrap_or(IMAGE_HEIGHT);
+    if !(MIN_IMAGE_SIZE..=MAX_IMAGE_SIZE).contains(&width)
+        || !(MIN_IMAGE_SIZE..=MAX_IMAGE_SIZE).contains(&height)
+    {
+        return Err(format!(
+            "width and height must be between {MIN_IMAGE_SIZE} and {MAX_IMAGE_SIZE}"
+        ));
+    }
+    let quality = query_u8(input, "quality", 1, 100)?
+        .or(variant.quality)
+        .unwrap_or(DEFAULT_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err("quality must be between 1 and 100".to_string());
+    }
+    // WebP は可逆圧縮でしか書き出せないので、黙って無視せずに指定を断る
+    if format == OutputFormat::WebP && query(input, "quality").is_some() {
+        return Err("quality cannot be combined with format=webp".to_string());
+    }
+
+    // padding は左右の余白をまとめて指定する (em は本文の文字の大きさを基準にする)
+    let padding = query_length(input, "padding")?;
+    let (margin_left, margin_right) = match (padding, query(input, "textwidth")) {
+        (Some(_), Some(_)) => {
+            return Err("padding parameter cannot be combined with textwidth".to_string());
+        }
+        (Some(padding), None) => {
+            if query(input, "marginleft").is_some() || query(input, "marginright").is_some() {
+                return Err(
+                    "padding parameter cannot be combined with marginleft or marginright"
+                        .to_string(),
+                );
+            }
+            let padding = padding.resolve(TEXT_FONT_SIZE);
+            (padding, padding)
+        }
+        (None, Some(text_width)) => {
+            if query(input, "marginleft").is_some() || query(input, "marginright").is_some() {
+                return Err(
+                    "textwidth parameter cannot be combined with marginleft or marginright"
+                        .to_string(),
+                );
+            }
+            // キャンバス幅に対する割合で指定し、左右中央に配置する
+            let percent = match text_width
+                .strip_suffix('%')
+                .unwrap_or(&text_width)
+                .parse::<f32>()
+            {
+                Ok(percent) if (10.0..=100.0).contains(&percent) => percent,
+                _ => return Err("textwidth must be between 10% and 100%".to_string()),
+            };
+            let margin = width as f32 * (100.0 - percent) / 200.0;
+            (margin, margin)
+        }
+        (None, None) => (
+            query_number(input, "marginleft")?.unwrap_or(DEFAULT_MARGIN_LEFT),
+            query_number(input, "marginright")?.unwrap_or(DEFAULT_MARGIN_RIGHT),
+        ),
+    };
+    if !(margin_left >= 0.0 && margin_right >= 0.0) {
+        return Err("margins must not be negative".to_string());
+    }
+    if margin_left + margin_right >= width as f32 {
+        return Err("margins are too large for the image width".to_string());
+    }
+
+    // tracking と linespacing の em はそれぞれの文字の大きさを基準にする
+    let tracking = query_length(input, "tracking")?.unwrap_or(Length::Px(0.0));
+    if !(-20.0..=50.0).contains(&tracking.resolve(TEXT_FONT_SIZE)) {
+        return Err("tracking is out of range".to_string());
+    }
+    let leading = query_length(input, "linespacing")?.unwrap_or(Length::Px(0.0));
+    if !(0.0..=100.0).contains(&leading.resolve(TEXT_FONT_SIZE)) {
+        return Err("linespacing is out of range".to_string());
+    }
+
+    // title と本文の y (80, 230) をそれぞれの上端とするかベースラインとするか
+    let anchor = parse_enum_param(input, "anchor", Anchor::from_param, Anchor::Top)?;
+
+    // justify は本文の折り返した行を max_width まで広げる (最後の行と改行の前の行はそのまま)
+    let align = parse_enum_param(input, "align", Align::from_param, Align::Left)?;
+
+    let position = parse_enum_param(
+        input,
+        "position",
+        VerticalPosition::from_param,
+        VerticalPosition::Top,
+    )?;
+
+    let badge = match query(input, "badge") {
+        Some(badge) => {
+            if badge.chars().count() > 30 {
+                return Err("badge parameter is too long".to_string());
+            }
+            Some(badge)
+        }
+        None => None,
+    };
+
+    // category は左上の端から伸びるタブに描く (色は categorycolor、文字は読みやすい色を選ぶ)
+    let category = match query(input, "category") {
+        Some(category) if category.trim().is_empty() => None,
+        Some(category) => {
+            if category.chars().count() > 20 {
+                return Err("category parameter is too long".to_string());
+            }
+            Some(Category {
+                text: category,
+                color: query_color(input, "categorycolor", DEFAULT_HIGHLIGHT_COLOR)?,
+            })
+        }
+        None => {
+            if query(input, "categorycolor").is_some() {
+                return Err("categorycolor parameter requires category".to_string());
+            }
+            None
+        }
+    };
+    // 下書きなどの目印に全体へ繰り返し薄く描く
+    let watermark = match query(input, "watermark") {
+        Some(watermark) if watermark.trim().is_empty() => None,
+        Some(watermark) if watermark.chars().count() > 30 => {
+            return Err("watermark parameter is too long".to_string());
+        }
+        watermark => watermark,
+    };
+
+    // "太さ,色" 形式 (例: 1,cccccc)
+    let rule = match query(input, "rule") {
+        Some(rule) => {
+            let (thickness, color) = rule
+                .split_once(',')
+                .ok_or("rule parameter is invalid".to_string())?;
+            let thickness = match thickness.parse::<f32>() {
+                Ok(thickness) if (1.0..=20.0).contains(&thickness) => thickness,
+                _ => return Err("rule thickness must be between 1 and 20".to_string()),
+            };
+            let color = parse_color(color).ok_or("rule parameter is invalid".to_string())?;
+            Some(Rule { thickness, color })
+        }
+        None => None,
+    };
+
+    let accent_count = query_u8(input, "accentcount", 1, MAX_ACCENT_COUNT)?.unwrap_or(1);
+    let accent_position = parse_enum_param(
+        input,
+        "accentposition",
+        AccentPosition::from_param,
+        AccentPosition::Top,
+    )?;
+    let accent = match query(input, "accent") {
+        Some(accent) => {
+            let color = parse_color(&accent).ok_or("accent parameter is invalid".to_string())?;
+            Some(Accent {
+                color,
+                count: accent_count.into(),
+                position: accent_position,
+            })
+        }
+        None => None,
+    };
+
+    // "dx,dy,ぼかし,色" 形式 (例: 2,2,4,000000)
+    let shadow = match query(input, "shadow") {
+        Some(shadow) => {
+            let parts: Vec<&str> = shadow.split(',').collect();
+            let [dx, dy, blur, color] = parts[..] else {
+                return Err("shadow parameter is invalid".to_string());
+            };
+            let offset = |value: &str| match value.parse::<f32>() {
+                Ok(value) if (-50.0..=50.0).contains(&value) => Ok(value),
+                _ => Err("shadow offset must be between -50 and 50".to_string()),
+            };
+            let blur = match blur.parse::<f32>() {
+                Ok(blur) if (0.0..=MAX_BLUR).contains(&blur) => blur,
+                _ => return Err(format!("shadow blur must be between 0 and {MAX_BLUR}")),
+            };
+            Some(Shadow {
+                offset: point(offset(dx)?, offset(dy)?),
+                blur,
+                color: parse_color(color).ok_or("shadow parameter is invalid".to_string())?,
+            })
+        }
+        None => None,
+    };
+    // fill=none は stroke と組み合わせて中抜きの文字にする
+    let hollow = parse_enum_param(
+        input,
+        "fill",
+        |s| match s {
+            "solid" => Some(false),
+            "none" => Some(true),
+            _ => None,
+        },
+        false,
+    )?;
+    let stroke = match query(input, "stroke") {
+        Some(stroke) => {
+            let (width, color) = match stroke.split_once(',') {
+                Some((width, color)) => (
+                    width,
+                    Some(parse_color(color).ok_or("stroke parameter is invalid".to_string())?),
+                ),
+                None => (stroke.as_str(), None),
+            };
+            let width = match width.parse::<f32>() {
+                Ok(width) if (1.0..=MAX_STROKE_WIDTH).contains(&width) => width,
+                _ => {
+                    return Err(format!(
+                        "stroke width must be between 1 and {MAX_STROKE_WIDTH}"
+                    ))
+                }
+            };
+            Some(Stroke {
+                width,
+                color,
+                hollow,
+            })
+        }
+        None if hollow => return Err("fill=none requires the stroke parameter".to_string()),
+        None => None,
+    };
+
+    // debug=bounds は文字の範囲と余白を枠で重ねて描く (DEBUG 環境変数がなければ使えない)
+    let debug_bounds =
+        parse_enum_param(input, "debug", |s| (s == "bounds").then_some(true), false)?;
+    // aa=false はドット絵のようにカバレッジを 0.5 で切って縁をぼかさない
+    let antialias = parse_enum_param(
+        input,
+        "aa",
+        |s| match s {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        },
+        true,
+    )?;
+
+    // textbg は panel と同じく本文の後ろに敷く (両方あれば textbg を使う)
+    let title_panel = query_panel(input, "titlebg")?;
+    let panel = match query_panel(input, "textbg")? {
+        Some(panel) => Some(panel),
+        None => query_panel(input, "panel")?,
+    };
+
+    let template = match query(input, "template") {
+        Some(template) => {
+            if template.is_empty()
+                || template.starts_with('.')
+                || !template
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            {
+                return Err("template parameter is invalid".to_string());
+            }
+            Some(template)
+        }
+        None => None,
+    };
+    // titlefont, textfont, authorfont は FONTS で許可されたものに限る (main で確認する)
+    let [title_font, text_font, author_font] = ["titlefont", "textfont", "authorfont"]
+        .map(|key| query(input, key).filter(|font| !font.trim().is_empty()));
+
+    // 取得先のホストは BGIMAGE_HOSTS で許可されたものに限る (main で確認する)
+    let bg_image = match query(input, "bgimage") {
+        Some(_) if template.is_some() => {
+            return Err("bgimage and template parameters cannot be combined".to_string());
+        }
+        Some(bg_image) => match Url::parse(&bg_image) {
+            Ok(bg_image) if bg_image.scheme() == "https" => Some(bg_image),
+            _ => return Err("bgimage must be an https URL".to_string()),
+        },
+        None => None,
+    };
+    // 大きすぎるとレンダリングに時間がかかるので上限を超える値は受け付けない
+    let blur = query_number::<f32>(input, "blur")?.unwrap_or(0.0);
+    if blur.is_nan() || blur < 0.0 {
+        return Err("blur parameter is invalid".to_string());
+    }
+    if blur > MAX_BLUR {
+        return Err(format!("blur must be between 0 and {MAX_BLUR}"));
+    }
+
+    let filter = parse_enum_param(input, "filter", |s| Filter::from_param(s).map(Some), None)?;
+
+    let flip_horizontal = parse_enum_param(input, "flip", |s| (s == "h").then_some(true), false)?;
+
+    // template の位置に合わせて微調整できるように、計算した位置からさらにずらす
+    let mut offset = point(0.0, 0.0);
+    for (key, value) in [("offsetx", &mut offset.x), ("offsety", &mut offset.y)] {
+        *value = query_number::<f32>(input, key)?.unwrap_or(0.0);
+        if !(-MAX_OFFSET..=MAX_OFFSET).contains(value) {
+            return Err(format!(
+                "{key} must be between -{MAX_OFFSET} and {MAX_OFFSET}"
+            ));
+        }
+    }
+
+    // 画像より大きい値は描画時に丸める
+    let radius = query_number::<f32>(input, "radius")?.unwrap_or(0.0);
+    if radius.is_nan() || radius < 0.0 {
+        return Err("radius parameter is invalid".to_string());
+    }
+
+    let dpi = query_number(input, "dpi")?;
+    if let Some(dpi) = dpi {
+        if !(MIN_DPI..=MAX_DPI).contains(&dpi) {
+            return Err(format!("dpi must be between {MIN_DPI} and {MAX_DPI}"));
+        }
+    }
+
+    // print=1 はレイアウトはそのままで PRINT_WIDTH の幅に拡大して描画する
+    let print = query_flag(input, "print");
+    let (scale, dpi) = if print {
+        if format != OutputFormat::Png {
+            return Err("print parameter requires png format".to_string());
+        }
+        let scale = PRINT_WIDTH as f32 / width as f32;
+        if (width as f32 * scale) * (height as f32 * scale) > MAX_PRINT_PIXELS as f32 {
+            return Err("image is too large to print".to_string());
+        }
+        (scale, dpi.or(Some(PRINT_DPI)))
+    } else {
+        (1.0, dpi)
+    };
+
+    // 空の四角はカードの見た目を損なうので、既定では描画しない
+    let notdef = parse_enum_param(input, "notdef", Notdef::from_param, Notdef::Skip)?;
+
+    // JPEG の色差成分の間引き方 (image crate の encoder は 444 しか書けない)
+    if query(input, "subsampling").is_some() && format != OutputFormat::Jpeg {
+        return Err("subsampling parameter requires jpeg format".to_string());
+    }
+    let subsampling = parse_enum_param(
+        input,
+        "subsampling",
+        Subsampling::from_param,
+        Subsampling::Yuv444,
+    )?;
+
+    // alpha=0 の場合はアルファチャンネルのない画像を出力する
+    let strip_alpha = parse_enum_param(
+        input,
+        "alpha",
+        |s| match s {
+            "0" => Some(true),
+            "1" => Some(false),
+            _ => None,
+        },
+        false,
+    )?;
+
+    let background = query_color(input, "bg", DEFAULT_BACKGROUND_COLOR)?;
+    let color = query_color(input, "color", DEFAULT_TEXT_COLOR)?;
+    let text_color = query_color(input, "textcolor", color)?;
+    let title_color = query_color(input, "titlecolor", color)?;
+    // footer=height,color は下端に帯を敷き、author と sitename をその中に置く
+    let footer = match query(input, "footer") {
+        Some(footer) => {
+            let (footer_height, footer_color) = footer
+                .split_once(',')
+                .ok_or("footer parameter is invalid".to_string())?;
+            let footer_height = match footer_height.parse::<f32>() {
+                Ok(footer_height)
+                    if (40.0..=300.0).contains(&footer_height)
+                        && footer_height <= height as f32 / 2.0 =>
+                {
+                    footer_height
+                }
+                _ => return Err(
+                    "footer height must be between 40 and 300 and at most half the image height"
+                        .to_string(),
+                ),
+            };
+            let footer_color =
+                parse_color(footer_color).ok_or("footer parameter is invalid".to_string())?;
+            if query(input, "stat").is_some() {
+                return Err("footer and stat parameters cannot be combined".to_string());
+            }
+            Some(Footer {
+                height: footer_height,
+                color: footer_color,
+            })
+        }
+        None => None,
+    };
+    // authorpill=rrggbb は author を角丸の背景の中に描く
+    let author_pill = match query(input, "authorpill") {
+        Some(pill) => {
+            if author.is_none() {
+                return Err("authorpill parameter requires author".to_string());
+            }
+            Some(parse_color(&pill).ok_or("authorpill parameter is invalid".to_string())?)
+        }
+        None => None,
+    };
+    // authorcolor がなければ author の背景 (pill か帯) の色に対して読みやすい色を選ぶ
+    let author_color = query_color(
+        input,
+        "authorcolor",
+        match (author_pill, footer) {
+            (Some(pill), _) => contrast_color(pill),
+            (None, Some(footer)) => contrast_color(footer.color),
+            (None, None) => color,
+        },
+    )?;
+
+    // title のうち highlight に一致する部分を highlightcolor で描く
+    let highlight = query(input, "highlight").filter(|highlight| !highlight.is_empty());
+    if highlight.is_none() && query(input, "highlightcolor").is_some() {
+        return Err("highlightcolor parameter requires highlight".to_string());
+    }
+    let highlight_color = query_color(input, "highlightcolor", DEFAULT_HIGHLIGHT_COLOR)?;
+    // "始まりの色,終わりの色" 形式 (例: ff0080,7928ca)
+    let text_gradient = match query(input, "textgradient") {
+        Some(gradient) => {
+            let colors = gradient
+                .split_once(',')
+                .and_then(|(from, to)| Some((parse_color(from)?, parse_color(to)?)));
+            let Some((from, to)) = colors else {
+                return Err("textgradient parameter is invalid".to_string());
+            };
+            Some(Gradient { from, to })
+        }
+        None => None,
+    };
+
+    let params = Params {
+        width,
+        height,
+        format,
+        quality,
+        title,
+        title_max_lines,
+        title_min_size,
+        title_fit,
+        highlight,
+        highlight_color,
+        text_gradient,
+        text,
+        columns,
+        max_lines,
+        overflow,
+        items,
+        list_style,
+        author,
+        stat,
+        stat_label,
+        site_name,
+        background,
+        title_color,
+        text_color,
+        author_color,
+        author_pill,
+        title_font,
+        text_font,
+        author_font,
+        footer,
+        margin_left,
+        margin_right,
+        offset,
+        tracking,
+        tabular: query_flag(input, "tabular"),
+        leading,
+        anchor,
+        align,
+        position,
+        badge,
+        category,
+        watermark,
+        rule,
+        accent,
+        shadow,
+        stroke,
+        panel,
+        title_panel,
+        template,
+        bg_image,
+        blur,
+        filter,
+        flip_horizontal,
+        radius,
+        dpi,
+        scale,
+        notdef,
+        subsampling,
+        strip_alpha,
+        gamma: query_flag(input, "gamma"),
+        antialias,
+        embed_meta: query_flag(input, "embedmeta"),
+        noindex: query_flag(input, "noindex"),
+        debug_bounds,
+    };
+    // SVG は文字と矩形をパスで書き出すだけなので、画像の合成やぼかしが必要なものは使えない
+    if params.format == OutputFormat::Svg {
+        for (name, used) in [
+            ("template", params.template.is_some()),
+            ("bgimage", params.bg_image.is_some()),
+            ("shadow", params.shadow.is_some()),
+            ("filter", params.filter.is_some()),
+            ("print", params.scale != 1.0),
+        ] {
+            if used {
+                return Err(format!("{name} cannot be combined with format=svg"));
+            }
+        }
+    }
+    // Workers の Date.now() は同期処理の間は進まないので、描画中に経過時間で打ち切ることはできない
+    // CPU 時間の上限で中断される前に、入力から見積もった重さで断る
+    if params.render_cost() > MAX_RENDER_COST {
+        return Err(
+            "image is too expensive to render; reduce the size, blur, shadow or use png"
+                .to_string(),
+        );
+    }
+    Ok(params)
+}
+
+// {name} をパラメータの値で、{1}, {2}, ... を arg パラメータの値で (指定した順に) 置き換える
+// 波括弧そのものは {{ と }} で書く
+fn fill_template(
+    template: &str,
+    input: &[(String, String)],
+) -> std::result::Result<String, String> {
+    let args: Vec<&str> = input
+        .iter()
+        .filter(|(k, _)| k == "arg")
+        .map(|(_, v)| v.as_str())
+        .collect();
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        filled.push_str(&rest[..start]);
+        if rest[start..].starts_with("{{") || rest[start..].starts_with("}}") {
+            filled.push_str(&rest[start..start + 1]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let end = match rest[start..].find('}') {
+            Some(end) if rest[start..].starts_with('{') => start + end,
+            _ => return Err("texttemplate has unbalanced braces".to_string()),
+        };
+        let name = &rest[start + 1..end];
+        let value = match name.parse::<usize>() {
+            Ok(index) if index >= 1 => args.get(index - 1).map(|arg| arg.to_string()),
+            Ok(_) => None,
+            Err(_) if name == "texttemplate" => None,
+            Err(_) => query(input, name),
+        };
+        match value {
+            Some(value) => filled.push_str(&value),
+            None => return Err(format!("texttemplate references unknown parameter {name}")),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+// 最初の "# " で始まる行を title に、残りを段落ごとに改行で区切った本文にする
+// 見出しがなければ全体を本文として扱う
+fn split_markdown(md: &str) -> (Option<String>, String) {
+    let mut heading = None;
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    for line in md.lines().map(str::trim) {
+        if heading.is_none() {
+            if let Some(title) = line.strip_prefix("# ") {
+                heading = Some(title.trim().to_string());
+                continue;
+            }
+        }
+        if line.is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(paragraph.join(" "));
+                paragraph.clear();
+            }
+        } else {
+            paragraph.push(line);
+        }
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph.join(" "));
+    }
+    (heading, paragraphs.join("\n"))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum VerticalPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
+impl VerticalPosition {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "top" => Some(VerticalPosition::Top),
+            "center" => Some(VerticalPosition::Center),
+            "bottom" => Some(VerticalPosition::Bottom),
+            _ => None,
+        }
+    }
+}
+
+fn generate_card<F: Font>(
+    font: &F,
+    params: &Params,
+    template: Option<&DynamicImage>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    generate_card_with_fonts(CardFonts::uniform(font), params, template)
+}
+
+// stat や badge など title と author 以外は本文のフォントで描く
+fn generate_card_with_fonts<F: Font>(
+    fonts: CardFonts<'_, F>,
+    params: &Params,
+    template: Option<&DynamicImage>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let mut imgbuf = match template {
+        Some(template) => render_template(template, params),
+        None => {
+            let (r, g, b) = params.background;
+            let (width, height) = params.canvas_size();
+            ImageBuffer::from_pixel(width, height, Rgba([r, g, b, 255]))
+        }
+    };
+    draw_card(fonts, params, &mut imgbuf);
+    if let Some(filter) = params.filter {
+        apply_filter(&mut imgbuf, filter);
+    }
+    if params.flip_horizontal {
+        image::imageops::flip_horizontal_in_place(&mut imgbuf);
+    }
+    if params.radius > 0.0 {
+        round_corners(&mut imgbuf, params.radius * params.scale);
+    }
+    imgbuf
+}
+
+// 背景の上に矩形と文字を描く (ラスタ画像と SVG で共通のレイアウト)
+fn draw_card<F: Font, C: Canvas>(fonts: CardFonts<'_, F>, params: &Params, canvas: &mut C) {
+    let font = fonts.text;
+    let stripped;
+    let params = match params.notdef {
+        Notdef::Skip => {
+            stripped = params.without_missing_glyphs(fonts);
+            &stripped
+        }
+        Notdef::Box => params,
+    };
+    let max_width = params.width as f32 - params.margin_left - params.margin_right;
+    let layout = LayoutOptions {
+        tracking: params.tracking,
+        leading: params.leading,
+        tabular: params.tabular,
+        ..LayoutOptions::default()
+    }
+    .with_max_width(max_width);
+    // anchor は title と本文にだけ使い、下端に揃える author などには使わない
+    let body_layout = LayoutOptions {
+        anchor: params.anchor,
+        ..layout
+    };
+    // align は本文にだけ使う
+    let text_layout = LayoutOptions {
+        align: params.align,
+        ..body_layout
+    };
+    let title_position = point(params.margin_left, 80.0);
+    let text_position = point(params.margin_left, 230.0);
+    // sitename があれば author を左に寄せ、sitename を右端に揃えて同じ行に並べる
+    let footer_y = match params.footer {
+        // 帯の中で author の行が上下中央に来るようにする
+        Some(footer) => {
+            let author_height = fonts.author.as_scaled(PxScale::from(60.0)).height();
+            params.height as f32 - footer.height + (footer.height - author_height) / 2.0
+        }
+        None => params.height as f32 - 130.0,
+    };
+    let site_name_position = params.site_name.as_ref().map(|site_name| {
+        let width = measure_text(
+            fonts.author,
+            PxScale::from(60.0),
+            site_name,
+            point(0.0, 0.0),
+            LayoutOptions::default(),
+        )
+        .map_or(0.0, |bounds| bounds.width());
+        point(params.width as f32 - params.margin_right - width, footer_y)
+    });
+    let author_position = match site_name_position {
+        Some(_) => point(params.margin_left, footer_y),
+        None => point(params.width as f32 - 200.0, footer_y),
+    };
+    // stat は左下に author と下端を揃えて置く
+    let stat_position = point(
+        params.margin_left,
+        author_position.y - (STAT_FONT_SIZE - 60.0),
+    );
+    // author も stat も sitename もなければ下の領域も本文に使う (footer があればその上まで)
+    let bottom_limit = match (
+        params.footer,
+        &params.author,
+        &params.stat,
+        &params.site_name,
+    ) {
+        (Some(footer), _, _, _) => params.height as f32 - footer.height - TEXT_GAP,
+        (None, _, Some(_), _) => stat_position.y - TEXT_GAP,
+        (None, Some(_), None, _) | (None, None, None, Some(_)) => author_position.y - TEXT_GAP,
+        (None, None, None, None) => params.height as f32 - TEXT_MARGIN,
+    };
+
+    // title と text をひとまとまりとして縦方向の位置を決める (author は右下に固定)
+    let mut text_font = font.as_scaled(PxScale::from(TEXT_FONT_SIZE));
+    let mut text_glyphs = Vec::new();
+    if let Some((left, right)) = &params.columns {
+        let column_width = (max_width - COLUMN_GUTTER) / 2.0;
+        let column_layout = text_layout
+            .with_max_width(column_width)
+            .with_max_lines(params.max_lines, params.overflow);
+        let left_scale = layout_text(
+            font,
+            text_font.scale,
+            text_position,
+            column_layout,
+            left,
+            &mut text_glyphs,
+        );
+        let right_scale = layout_text(
+            font,
+            text_font.scale,
+            point(
+                text_position.x + column_width + COLUMN_GUTTER,
+                text_position.y,
+            ),
+            column_layout,
+            right,
+            &mut text_glyphs,
+        );
+        // 縦方向の範囲は大きい方の段に合わせて求める
+        text_font = font.as_scaled(if left_scale.y >= right_scale.y {
+            left_scale
+        } else {
+            right_scale
+        });
+    } else if params.items.is_empty() {
+        text_font = font.as_scaled(layout_text(
+            font,
+            text_font.scale,
+            text_position,
+            text_layout.with_max_lines(params.max_lines, params.overflow),
+            &params.text,
+            &mut text_glyphs,
+        ));
+    } else {
+        layout_list(
+            text_font,
+            text_position,
+            text_layout,
+            &params.items,
+            params.list_style,
+            &mut text_glyphs,
+        );
+    }
+
+    let title_scale = fit_title_scale(fonts.title, params, title_position, body_layout);
+    let title_layout = title_layout(params, body_layout);
+
+    let title_bounds = measure_text(
+        fonts.title,
+        title_scale,
+        &params.title,
+        title_position,
+        title_layout,
+    );
+    let text_bounds = glyph_bounds(text_font, &text_glyphs);
+    let offset_y = match union_bounds(title_bounds, text_bounds) {
+        Some(bounds) => match params.position {
+            VerticalPosition::Top => 0.0,
+            VerticalPosition::Center => {
+                (params.height as f32 - bounds.height()) / 2.0 - bounds.min.y
+            }
+            VerticalPosition::Bottom => bottom_limit - bounds.max.y,
+        },
+        None => 0.0,
+    };
+
+    if let Some(watermark) = &params.watermark {
+        render_watermark(font, canvas, watermark, params);
+    }
+    if let Some(footer) = params.footer {
+        let rect = Rect {
+            min: point(0.0, params.height as f32 - footer.height),
+            max: point(params.width as f32, params.height as f32),
+        };
+        canvas.fill_rect(params.canvas_rect(rect), 0.0, footer.color, 1.0);
+    }
+    // 帯や透かしは動かさず、文字とそれに沿って敷くものだけを offset だけずらす
+    let mut shifted = OffsetCanvas {
+        canvas: &mut *canvas,
+        offset: params.offset,
+        scale: params.scale,
+    };
+    // 計測した title と本文の範囲に余白を足した大きさで敷く
+    for (panel, bounds) in [
+        (params.title_panel, title_bounds),
+        (params.panel, text_bounds),
+    ] {
+        let (Some(panel), Some(bounds)) = (panel, bounds) else {
+            continue;
+        };
+        let rect = Rect {
+            min: point(
+                bounds.min.x - PANEL_PADDING,
+                bounds.min.y + offset_y - PANEL_PADDING,
+            ),
+            max: point(
+                bounds.max.x + PANEL_PADDING,
+                bounds.max.y + offset_y + PANEL_PADDING,
+            ),
+        };
+        shifted.fill_rect(
+            params.canvas_rect(rect),
+            PANEL_RADIUS * params.scale,
+            panel.color,
+            panel.opacity,
+        );
+    }
+    // 計測した title の左上の角を基準に並べる
+    if let (Some(accent), Some(title_bounds)) = (params.accent, title_bounds) {
+        let top = title_bounds.min.y + offset_y;
+        for i in 0..accent.count {
+            let rect = match accent.position {
+                AccentPosition::Top => {
+                    let x = title_bounds.min.x + i as f32 * (ACCENT_LENGTH + ACCENT_GAP);
+                    let y = top - ACCENT_GAP - ACCENT_THICKNESS;
+                    Rect {
+                        min: point(x, y),
+                        max: point(x + ACCENT_LENGTH, y + ACCENT_THICKNESS),
+                    }
+                }
+                AccentPosition::Left => {
+                    let x = title_bounds.min.x - (i + 1) as f32 * (ACCENT_GAP + ACCENT_THICKNESS);
+                    Rect {
+                        min: point(x, top),
+                        max: point(x + ACCENT_THICKNESS, top + ACCENT_LENGTH),
+                    }
+                }
+            };
+            shifted.fill_rect(params.canvas_rect(rect), 0.0, accent.color, 1.0);
+        }
+    }
+    // 区切り線は title と本文の間の中央に引く
+    if let (Some(rule), Some(title_bounds), Some(text_bounds)) =
+        (params.rule, title_bounds, text_bounds)
+    {
+        let center = (title_bounds.max.y + text_bounds.min.y) / 2.0 + offset_y;
+        let rect = Rect {
+            min: point(params.margin_left, center - rule.thickness / 2.0),
+            max: point(
+                params.margin_left + max_width,
+                center + rule.thickness / 2.0,
+            ),
+        };
+        shifted.fill_rect(params.canvas_rect(rect), 0.0, rule.color, 1.0);
+    }
+    // debug=bounds で枠を描く範囲 (描画の最後に重ねる)
+    let mut debug_boxes = Vec::new();
+    if params.debug_bounds {
+        let content = Rect {
+            min: point(params.margin_left, title_position.y),
+            max: point(params.margin_left + max_width, bottom_limit),
+        };
+        debug_boxes.push((content, DEBUG_MARGIN_COLOR));
+        let shift = |bounds: Rect| Rect {
+            min: point(bounds.min.x, bounds.min.y + offset_y),
+            max: point(bounds.max.x, bounds.max.y + offset_y),
+        };
+        debug_boxes.extend(title_bounds.map(|bounds| (shift(bounds), DEBUG_TEXT_COLOR)));
+        debug_boxes.extend(text_bounds.map(|bounds| (shift(bounds), DEBUG_TEXT_COLOR)));
+    }
+    for glyph in &mut text_glyphs {
+        glyph.position.y += offset_y;
+    }
+    shifted.draw_glyphs(font, text_glyphs, params.text_paint(params.text_color));
+    let mut title_glyphs = Vec::new();
+    layout_paragraph(
+        fonts.title.as_scaled(title_scale),
+        point(title_position.x, title_position.y + offset_y),
+        title_layout,
+        &params.title,
+        &mut title_glyphs,
+    );
+    let highlighted = match &params.highlight {
+        Some(word) => highlight_mask(&params.title, word),
+        None => Vec::new(),
+    };
+    let (highlighted_glyphs, title_glyphs): (Vec<_>, Vec<_>) = title_glyphs
+        .into_iter()
+        .enumerate()
+        .partition(|(i, _)| highlighted.get(*i).copied().unwrap_or(false));
+    shifted.draw_glyphs(
+        fonts.title,
+        title_glyphs.into_iter().map(|(_, glyph)| glyph).collect(),
+        params.text_paint(params.title_color),
+    );
+    shifted.draw_glyphs(
+        fonts.title,
+        highlighted_glyphs
+            .into_iter()
+            .map(|(_, glyph)| glyph)
+            .collect(),
+        params.paint(params.highlight_color),
+    );
+    if let Some(author) = &params.author {
+        // sitename と重ならない幅で折り返す
+        let author_layout = match site_name_position {
+            Some(position) => layout.with_max_width(position.x - author_position.x - TEXT_GAP),
+            None => layout,
+        };
+        if params.debug_bounds {
+            let bounds = measure_text(
+                fonts.author,
+                PxScale::from(60.0),
+                author,
+                author_position,
+                author_layout,
+            );
+            debug_boxes.extend(bounds.map(|bounds| (bounds, DEBUG_TEXT_COLOR)));
+        }
+        // 計測した author の範囲に余白を足して丸く敷き、その中央に文字が来るようにする
+        if let Some(pill) = params.author_pill {
+            let padding = point(24.0, 12.0);
+            if let Some(bounds) = measure_text(
+                fonts.author,
+                PxScale::from(60.0),
+                author,
+                author_position,
+                author_layout,
+            ) {
+                let rect = Rect {
+                    min: point(bounds.min.x - padding.x, bounds.min.y - padding.y),
+                    max: point(bounds.max.x + padding.x, bounds.max.y + padding.y),
+                };
+                shifted.fill_rect(
+                    params.canvas_rect(rect),
+                    rect.height() / 2.0 * params.scale,
+                    pill,
+                    1.0,
+                );
+            }
+        }
+        render_text(
+            fonts.author,
+            PxScale::from(60.0),
+            &mut shifted,
+            author,
+            // pill の上に描くときは影や線を付けない
+            TextPaint {
+                shadow: params.shadow.filter(|_| params.author_pill.is_none()),
+                stroke: params.stroke.filter(|_| params.author_pill.is_none()),
+                ..params.paint(params.author_color)
+            },
+            author_position,
+            author_layout,
+        );
+    }
+    if let (Some(site_name), Some(position)) = (&params.site_name, site_name_position) {
+        if params.debug_bounds {
+            let bounds = measure_text(
+                fonts.author,
+                PxScale::from(60.0),
+                site_name,
+                position,
+                LayoutOptions::default(),
+            );
+            debug_boxes.extend(bounds.map(|bounds| (bounds, DEBUG_TEXT_COLOR)));
+        }
+        render_text(
+            fonts.author,
+            PxScale::from(60.0),
+            &mut shifted,
+            site_name,
+            params.paint(params.author_color),
+            position,
+            LayoutOptions::default(),
+        );
+    }
+    if let Some(stat) = &params.stat {
+        let mut stat_glyphs = Vec::new();
+        let label = params
+            .stat_label
+            .as_ref()
+            .map(|label| format!(" {label}"))
+            .unwrap_or_default();
+        layout_runs(
+            &[
+                (font.as_scaled(PxScale::from(STAT_FONT_SIZE)), stat.as_str()),
+                (
+                    font.as_scaled(PxScale::from(STAT_LABEL_FONT_SIZE)),
+                    label.as_str(),
+                ),
+            ],
+            stat_position,
+            layout,
+            &mut stat_glyphs,
+        );
+        if params.debug_bounds {
+            let bounds = glyph_bounds(font.as_scaled(PxScale::from(STAT_FONT_SIZE)), &stat_glyphs);
+            debug_boxes.extend(bounds.map(|bounds| (bounds, DEBUG_TEXT_COLOR)));
+        }
+        shifted.draw_glyphs(font, stat_glyphs, params.paint(params.text_color));
+    }
+    if let Some(badge) = &params.badge {
+        render_badge(font, canvas, badge, params);
+    }
+    if let Some(category) = &params.category {
+        render_category(font, canvas, category, params);
+    }
+    let mut shifted = OffsetCanvas {
+        canvas,
+        offset: params.offset,
+        scale: params.scale,
+    };
+    for (rect, color) in debug_boxes {
+        outline_rect(
+            &mut shifted,
+            params.canvas_rect(rect),
+            DEBUG_LINE_WIDTH * params.scale,
+            color,
+        );
+    }
+}
+
+// 矩形の内側に沿って幅 width の枠を描く
+fn outline_rect<C: Canvas>(canvas: &mut C, rect: Rect, width: f32, color: (u8, u8, u8)) {
+    let Rect { min, max } = rect;
+    for side in [
+        Rect {
+            min,
+            max: point(max.x, min.y + width),
+        },
+        Rect {
+            min: point(min.x, max.y - width),
+            max,
+        },
+        Rect {
+            min,
+            max: point(min.x + width, max.y),
+        },
+        Rect {
+            min: point(max.x - width, min.y),
+            max,
+        },
+    ] {
+        canvas.fill_rect(side, 0.0, color, 1.0);
+    }
+}
+
+// title が title_max_lines 行に収まるまで文字を小さくする
+fn fit_title_scale<F: Font>(
+    font: &F,
+    params: &Params,
+    title_position: Point,
+    options: LayoutOptions,
+) -> PxScale {
+    if params.title_fit == TitleFit::Width {
+        return fit_title_width(font, params, title_position, options);
+    }
+    let mut size = TITLE_FONT_SIZE;
+    while size > params.title_min_size {
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            font.as_scaled(PxScale::from(size)),
+            title_position,
+            options,
+            &params.title,
+            &mut glyphs,
+        );
+        if count_lines(&glyphs) <= params.title_max_lines {
+            break;
+        }
+        size = (size - 2.0).max(params.title_min_size);
+    }
+    PxScale::from(size)
+}
+
+// 基準の大きさで折り返さずに測った幅から、幅が max_width になる大きさを求める
+// tracking の px は大きさに比例しないので、求めた大きさで測り直してもう 1 回合わせる
+fn fit_title_width<F: Font>(
+    font: &F,
+    params: &Params,
+    title_position: Point,
+    options: LayoutOptions,
+) -> PxScale {
+    let unwrapped = options.with_max_width(f32::INFINITY);
+    let mut size = TITLE_FONT_SIZE;
+    for _ in 0..2 {
+        let Some(bounds) = measure_text(
+            font,
+            PxScale::from(size),
+            &params.title,
+            title_position,
+            unwrapped,
+        ) else {
+            break;
+        };
+        if bounds.width() <= 0.0 {
+            break;
+        }
+        size = (size * options.max_width / bounds.width())
+            .clamp(params.title_min_size, MAX_FIT_TITLE_SIZE);
+    }
+    PxScale::from(size)
+}
+
+// fit=width の title は測ったときと同じく 1 行に並ぶように、幅に誤差の分だけ余裕を持たせる
+fn title_layout(params: &Params, options: LayoutOptions) -> LayoutOptions {
+    match params.title_fit {
+        TitleFit::Width => options.with_max_width(options.max_width + FIT_WIDTH_TOLERANCE),
+        TitleFit::Lines => options,
+    }
+}
+
+// layout_paragraph は制御文字以外の文字ごとに 1 つ glyph を作るので、同じ単位で一致した位置を返す
+fn highlight_mask(text: &str, word: &str) -> Vec<bool> {
+    let mut matched = vec![false; text.len()];
+    for (start, m) in text.match_indices(word) {
+        matched[start..start + m.len()].fill(true);
+    }
+    text.char_indices()
+        .filter(|(_, c)| !c.is_control())
+        .map(|(i, _)| matched[i])
+        .collect()
+}
+
+fn count_lines(glyphs: &[Glyph]) -> usize {
+    let mut baselines: Vec<f32> = glyphs.iter().map(|glyph| glyph.position.y).collect();
+    baselines.dedup();
+    baselines.len()
+}
+
+// キャンバス全体を覆うように拡大縮小してから必要ならぼかす
+fn render_template(template: &DynamicImage, params: &Params) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (width, height) = params.canvas_size();
+    let mut template =
+        template.resize_to_fill(width, height, image::imageops::FilterType::Triangle);
+    if params.blur > 0.0 {
+        template = template.blur(params.blur * params.scale);
+    }
+    let (r, g, b) = params.background;
+    let mut imgbuf = ImageBuffer::from_pixel(width, height, Rgba([r, g, b, 255]));
+    image::imageops::overlay(&mut imgbuf, &template.to_rgba8(), 0, 0);
+    imgbuf
+}
+
+// 右上に角丸の背景付きで描画する (背景は title の色、文字は背景色)
+fn render_badge<F: Font, C: Canvas>(font: &F, canvas: &mut C, badge: &str, params: &Params) {
+    let font_scale = PxScale::from(28.0);
+    let padding = point(16.0, 8.0);
+    let bounds = match measure_text(
+        font,
+        font_scale,
+        badge,
+        point(0.0, 0.0),
+        LayoutOptions::default(),
+    ) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let width = bounds.width() + padding.x * 2.0;
+    let height = bounds.height() + padding.y * 2.0;
+    let min = point(params.width as f32 - params.margin_right - width, 24.0);
+    let rect = Rect {
+        min,
+        max: point(min.x + width, min.y + height),
+    };
+    canvas.fill_rect(
+        params.canvas_rect(rect),
+        height / 2.0 * params.scale,
+        params.title_color,
+        1.0,
+    );
+    render_text(
+        font,
+        font_scale,
+        canvas,
+        badge,
+        // 背景の上に描くので影や線は付けない
+        TextPaint {
+            shadow: None,
+            stroke: None,
+            ..params.paint(params.background)
+        },
+        point(min.x + padding.x, min.y + padding.y),
+        LayoutOptions::default(),
+    );
+}
+
+// 左端から本文の左の余白の位置まで伸ばしたタブの上に描く
+fn render_category<F: Font, C: Canvas>(
+    font: &F,
+    canvas: &mut C,
+    category: &Category,
+    params: &Params,
+) {
+    let font_scale = PxScale::from(28.0);
+    let padding = point(16.0, 8.0);
+    let bounds = match measure_text(
+        font,
+        font_scale,
+        &category.text,
+        point(0.0, 0.0),
+        LayoutOptions::default(),
+    ) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let top = 24.0;
+    let rect = Rect {
+        min: point(0.0, top),
+        max: point(
+            params.margin_left + bounds.width() + padding.x,
+            top + bounds.height() + padding.y * 2.0,
+        ),
+    };
+    canvas.fill_rect(params.canvas_rect(rect), 0.0, category.color, 1.0);
+    render_text(
+        font,
+        font_scale,
+        canvas,
+        &category.text,
+        TextPaint {
+            shadow: None,
+            stroke: None,
+            ..params.paint(contrast_color(category.color))
+        },
+        point(params.margin_left, top + padding.y),
+        LayoutOptions::default(),
+    );
+}
+
+// 描画先 (ラスタ画像か SVG) ごとに矩形と文字の描き方を変える
+trait Canvas {
+    fn fill_rect(&mut self, rect: Rect, radius: f32, color: (u8, u8, u8), opacity: f32);
+    fn draw_glyphs<F: Font>(&mut self, font: &F, glyphs: Vec<Glyph>, paint: TextPaint);
+    // tile の大きさに並べた glyphs を、angle だけ傾けて行ごとに半分ずらしながら全体に敷き詰める
+    fn draw_tiled<F: Font>(
+        &mut self,
+        font: &F,
+        glyphs: Vec<Glyph>,
+        tile: (f32, f32),
+        paint: TextPaint,
+        opacity: f32,
+    );
+}
+
+impl Canvas for ImageBuffer<Rgba<u8>, Vec<u8>> {
+    fn fill_rect(&mut self, rect: Rect, radius: f32, color: (u8, u8, u8), opacity: f32) {
+        fill_rounded_rect(self, rect, radius, color, opacity);
+    }
+
+    fn draw_glyphs<F: Font>(&mut self, font: &F, glyphs: Vec<Glyph>, paint: TextPaint) {
+        *self = render_glyphs(font, glyphs, std::mem::take(self), paint);
+    }
+
+    fn draw_tiled<F: Font>(
+        &mut self,
+        font: &F,
+        glyphs: Vec<Glyph>,
+        tile: (f32, f32),
+        paint: TextPaint,
+        opacity: f32,
+    ) {
+        render_tiled(font, glyphs, self, tile, paint, opacity);
+    }
+}
+
+// 描くものをすべて offset (レイアウトの座標) だけずらして canvas に渡す
+struct OffsetCanvas<'a, C> {
+    canvas: &'a mut C,
+    offset: Point,
+    scale: f32,
+}
+
+impl<C: Canvas> Canvas for OffsetCanvas<'_, C> {
+    // rect はすでに scale 倍したキャンバスの座標で渡される
+    fn fill_rect(&mut self, rect: Rect, radius: f32, color: (u8, u8, u8), opacity: f32) {
+        let offset = point(self.offset.x * self.scale, self.offset.y * self.scale);
+        let rect = Rect {
+            min: rect.min + offset,
+            max: rect.max + offset,
+        };
+        self.canvas.fill_rect(rect, radius, color, opacity);
+    }
+
+    fn draw_glyphs<F: Font>(&mut self, font: &F, glyphs: Vec<Glyph>, paint: TextPaint) {
+        let glyphs = glyphs
+            .into_iter()
+            .map(|glyph| Glyph {
+                position: glyph.position + self.offset,
+                ..glyph
+            })
+            .collect();
+        self.canvas.draw_glyphs(font, glyphs, paint);
+    }
+
+    // 全体に敷き詰めるのでずらしても見た目は変わらない
+    fn draw_tiled<F: Font>(
+        &mut self,
+        font: &F,
+        glyphs: Vec<Glyph>,
+        tile: (f32, f32),
+        paint: TextPaint,
+        opacity: f32,
+    ) {
+        self.canvas.draw_tiled(font, glyphs, tile, paint, opacity);
+    }
+}
+
+// 描いたものを SVG の要素として書き出す (文字は輪郭をパスにする)
+struct SvgCanvas {
+    body: String,
+    // linearGradient の id を重ならないように振る
+    gradients: usize,
+}
+
+fn svg_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// ab_glyph の outline_glyph と同じようにフォントの座標 (y が上向き) を画像の座標に変換する
+fn svg_glyph_path<F: Font>(font: &F, glyphs: &[Glyph]) -> String {
+    let mut path = String::new();
+    for glyph in glyphs {
+        let Some(outline) = font.outline(glyph.id) else {
+            continue;
+        };
+        let scaled = font.as_scaled(glyph.scale);
+        let (h, v) = (scaled.h_scale_factor(), scaled.v_scale_factor());
+        let to_canvas = |p: Point| {
+            format!(
+                "{} {}",
+                glyph.position.x + p.x * h,
+                glyph.position.y - p.y * v
+            )
+        };
+        let mut last: Option<Point> = None;
+        for curve in outline.curves {
+            let (start, end) = match curve {
+                ab_glyph::OutlineCurve::Line(a, b) => (a, b),
+                ab_glyph::OutlineCurve::Quad(a, _, c) => (a, c),
+                ab_glyph::OutlineCurve::Cubic(a, _, _, d) => (a, d),
+            };
+            if last != Some(start) {
+                if last.is_some() {
+                    path.push('Z');
+                }
+                path.push_str(&format!("M{}", to_canvas(start)));
+            }
+            match curve {
+                ab_glyph::OutlineCurve::Line(_, b) => {
+                    path.push_str(&format!("L{}", to_canvas(b)));
+                }
+                ab_glyph::OutlineCurve::Quad(_, b, c) => {
+                    path.push_str(&format!("Q{} {}", to_canvas(b), to_canvas(c)));
+                }
+                ab_glyph::OutlineCurve::Cubic(_, b, c, d) => {
+                    path.push_str(&format!(
+                        "C{} {} {}",
+                        to_canvas(b),
+                        to_canvas(c),
+                        to_canvas(d)
+                    ));
+                }
+            }
+            last = Some(end);
+        }
+        if last.is_some() {
+            path.push('Z');
+        }
+    }
+    path
+}
+
+impl Canvas for SvgCanvas {
+    fn fill_rect(&mut self, rect: Rect, radius: f32, color: (u8, u8, u8), opacity: f32) {
+        let radius = radius.min(rect.width() / 2.0).min(rect.height() / 2.0);
+        self.body.push_str(&format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{radius}" fill="{}" fill-opacity="{opacity}"/>"#,
+            rect.min.x,
+            rect.min.y,
+            rect.width(),
+            rect.height(),
+            svg_color(color),
+        ));
+    }
+
+    fn draw_glyphs<F: Font>(&mut self, font: &F, glyphs: Vec<Glyph>, paint: TextPaint) {
+        let path = svg_glyph_path(font, &glyphs);
+        if path.is_empty() {
+            return;
+        }
+        let fill = match (paint.stroke, paint.gradient) {
+            (Some(stroke), _) if stroke.hollow => "none".to_string(),
+            // 既定の objectBoundingBox でパス全体の左端から右端にかけて変わる
+            (_, Some(gradient)) => {
+                self.gradients += 1;
+                let id = format!("gradient{}", self.gradients);
+                self.body.push_str(&format!(
+                    r#"<defs><linearGradient id="{id}"><stop offset="0" stop-color="{}"/><stop offset="1" stop-color="{}"/></linearGradient></defs>"#,
+                    svg_color(gradient.from),
+                    svg_color(gradient.to)
+                ));
+                format!("url(#{id})")
+            }
+            _ => svg_color(paint.color),
+        };
+        // SVG の線は輪郭の中心に引かれるのでラスタと同じ太さになる
+        let stroke = match paint.stroke {
+            Some(stroke) => format!(
+                r#" stroke="{}" stroke-width="{}" stroke-linejoin="round""#,
+                svg_color(stroke.color.unwrap_or(paint.color)),
+                stroke.width
+            ),
+            None => String::new(),
+        };
+        let rendering = if paint.antialias {
+            ""
+        } else {
+            r#" shape-rendering="crispEdges""#
+        };
+        self.body.push_str(&format!(
+            r#"<path d="{path}" fill="{fill}"{stroke}{rendering}/>"#
+        ));
+    }
+
+    // 2 行分を 1 つの pattern にして、2 行目は半分ずらして両端に描く
+    fn draw_tiled<F: Font>(
+        &mut self,
+        font: &F,
+        glyphs: Vec<Glyph>,
+        (width, height): (f32, f32),
+        paint: TextPaint,
+        opacity: f32,
+    ) {
+        let shifted = |dx: f32, dy: f32| -> Vec<Glyph> {
+            glyphs
+                .iter()
+                .map(|glyph| Glyph {
+                    position: point(glyph.position.x + dx, glyph.position.y + dy),
+                    ..glyph.clone()
+                })
+                .collect()
+        };
+        let path = [
+            shifted(0.0, 0.0),
+            shifted(width / 2.0, height),
+            shifted(-width / 2.0, height),
+        ]
+        .iter()
+        .map(|glyphs| svg_glyph_path(font, glyphs))
+        .collect::<String>();
+        self.body.push_str(&format!(
+            r#"<defs><pattern id="watermark" patternUnits="userSpaceOnUse" width="{width}" height="{}" patternTransform="rotate(-{WATERMARK_ANGLE})"><path d="{path}" fill="{}"/></pattern></defs><rect width="100%" height="100%" fill="url(#watermark)" opacity="{opacity}"/>"#,
+            height * 2.0,
+            svg_color(paint.color),
+        ));
+    }
+}
+
+// 角丸は clipPath、左右反転は transform で表す
+fn generate_svg<F: Font>(fonts: CardFonts<'_, F>, params: &Params) -> String {
+    let mut canvas = SvgCanvas {
+        body: String::new(),
+        gradients: 0,
+    };
+    draw_card(fonts, params, &mut canvas);
+
+    let (width, height) = (params.width, params.height);
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" role="img">"#
+    );
+    svg.push_str(&format!(
+        "<title>{}</title>",
+        escape_xml(&params.alt_text())
+    ));
+    if params.radius > 0.0 {
+        svg.push_str(&format!(
+            r#"<defs><clipPath id="card"><rect width="{width}" height="{height}" rx="{}"/></clipPath></defs><g clip-path="url(#card)">"#,
+            params.radius
+        ));
+    }
+    svg.push_str(&format!(
+        r#"<rect width="{width}" height="{height}" fill="{}"/>"#,
+        svg_color(params.background)
+    ));
+    if params.flip_horizontal {
+        svg.push_str(&format!(
+            r#"<g transform="translate({width} 0) scale(-1 1)">{}</g>"#,
+            canvas.body
+        ));
+    } else {
+        svg.push_str(&canvas.body);
+    }
+    if params.radius > 0.0 {
+        svg.push_str("</g>");
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn fill_rounded_rect(
+    imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    rect: Rect,
+    radius: f32,
+    color: (u8, u8, u8),
+    opacity: f32,
+) {
+    let radius = radius.min(rect.width() / 2.0).min(rect.height() / 2.0);
+    let x_range = rect.min.x.max(0.0) as u32..(rect.max.x.ceil() as u32).min(imgbuf.width());
+    let y_range = rect.min.y.max(0.0) as u32..(rect.max.y.ceil() as u32).min(imgbuf.height());
+    for y in y_range {
+        for x in x_range.clone() {
+            let coverage = rounded_rect_coverage(rect, radius, x, y) * opacity;
+            if coverage > 0.0 {
+                blend_pixel(imgbuf.get_pixel_mut(x, y), color, coverage);
+            }
+        }
+    }
+}
+
+// ピクセルの中心から最も近い角の円の中心までの距離でカバー率を求める
+fn rounded_rect_coverage(rect: Rect, radius: f32, x: u32, y: u32) -> f32 {
+    let cx = x as f32 + 0.5;
+    let cy = y as f32 + 0.5;
+    let dx = (rect.min.x + radius - cx)
+        .max(cx - (rect.max.x - radius))
+        .max(0.0);
+    let dy = (rect.min.y + radius - cy)
+        .max(cy - (rect.max.y - radius))
+        .max(0.0);
+    if dx > 0.0 && dy > 0.0 {
+        (radius - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0)
+    } else {
+        let edge = (cx - rect.min.x)
+            .min(rect.max.x - cx)
+            .min(cy - rect.min.y)
+            .min(rect.max.y - cy);
+        (edge + 0.5).clamp(0.0, 1.0)
+    }
+}
+
+// 角丸の外側を透明にする (角の付近だけ調べればよい)
+fn round_corners(imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, radius: f32) {
+    let (width, height) = imgbuf.dimensions();
+    let rect = Rect {
+        min: point(0.0, 0.0),
+        max: point(width as f32, height as f32),
+    };
+    let radius = radius.min(width as f32 / 2.0).min(height as f32 / 2.0);
+    let size = radius.ceil() as u32;
+    let near_edge = |v: u32, len: u32| v < size || v >= len - size;
+    for y in (0..height).filter(|&y| near_edge(y, height)) {
+        for x in (0..width).filter(|&x| near_edge(x, width)) {
+            let coverage = rounded_rect_coverage(rect, radius, x, y);
+            let px = imgbuf.get_pixel_mut(x, y);
+            px.0[3] = (px.0[3] as f32 * coverage).round() as u8;
+        }
+    }
+}
+
+// sRGB のまま線形補間すると暗い文字の輪郭が細く見えるので、
+// gamma が有効な場合はリニアな値に変換してから合成する
+fn blend_pixel_linear(px: &mut Rgba<u8>, color: (u8, u8, u8), v: f32) {
+    let blend = |dst: u8, src: u8| {
+        let linear = srgb_to_linear(dst) * (1.0 - v) + srgb_to_linear(src) * v;
+        linear_to_srgb(linear)
+    };
+    *px = Rgba([
+        blend(px.0[0], color.0),
+        blend(px.0[1], color.1),
+        blend(px.0[2], color.2),
+        255,
+    ]);
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn blend_pixel(px: &mut Rgba<u8>, color: (u8, u8, u8), v: f32) {
+    *px = Rgba([
+        (px.0[0] as f32 * (1.0 - v) + color.0 as f32 * v) as u8,
+        (px.0[1] as f32 * (1.0 - v) + color.1 as f32 * v) as u8,
+        (px.0[2] as f32 * (1.0 - v) + color.2 as f32 * v) as u8,
+        255,
+    ]);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Filter {
+    Grayscale,
+}
+
+impl Filter {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "grayscale" => Some(Filter::Grayscale),
+            _ => None,
+        }
+    }
+}
+
+// 描画が終わった画像全体に適用する
+fn apply_filter(imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, filter: Filter) {
+    for px in imgbuf.pixels_mut() {
+        match filter {
+            Filter::Grayscale => {
+                let luma =
+                    (0.299 * px.0[0] as f32 + 0.587 * px.0[1] as f32 + 0.114 * px.0[2] as f32)
+                        .round() as u8;
+                *px = Rgba([luma, luma, luma, px.0[3]]);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    WebP,
+    Svg,
+}
+
+impl OutputFormat {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::WebP),
+            "svg" => Some(OutputFormat::Svg),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Svg => "svg",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Svg => "image/svg+xml",
+        }
+    }
+}
+
+// 対応していれば WebP、そうでなければ PNG を選ぶ (q=0 は受け付けないものとして扱う)
+// format=auto に quality が付いている場合は、呼び出し側で WebP を PNG に置き換える
+fn negotiate_format(accept: &str) -> OutputFormat {
+    let accepts_webp = accept.split(',').any(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        parts.next() == Some("image/webp")
+            && !parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            })
+    });
+    if accepts_webp {
+        OutputFormat::WebP
+    } else {
+        OutputFormat::Png
+    }
+}
+
+fn encode_image(
+    imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    format: OutputFormat,
+    params: &Params,
+) -> image::ImageResult<Vec<u8>> {
+    let mut buffer = std::io::Cursor::new(vec![]);
+    match format {
+        OutputFormat::Png => return encode_png(imgbuf, params),
+        // JPEG はアルファチャンネルを扱えないので背景色と合成する
+        OutputFormat::Jpeg => {
+            let flattened = flatten_alpha(imgbuf, params.background);
+            let encoder = match params.subsampling {
+                Subsampling::Yuv444 => JpegEncoder::new_with_quality(&mut buffer, params.quality),
+            };
+            flattened.write_with_encoder(encoder)?
+        }
+        // image crate の WebP encoder は lossless しかサポートしていないので quality は使えない
+        OutputFormat::WebP if params.strip_alpha => flatten_alpha(imgbuf, params.background)
+            .write_to(&mut buffer, image::ImageFormat::WebP)?,
+        OutputFormat::WebP => imgbuf.write_to(&mut buffer, image::ImageFormat::WebP)?,
+        // SVG は generate_svg で書き出すのでラスタ画像からは作れない
+        OutputFormat::Svg => {
+            return Err(image::ImageError::Unsupported(
+                image::error::UnsupportedError::from_format_and_kind(
+                    image::error::ImageFormatHint::Name("svg".to_string()),
+                    image::error::UnsupportedErrorKind::Format(
+                        image::error::ImageFormatHint::Name("svg".to_string()),
+                    ),
+                ),
+            ))
+        }
+    }
+    Ok(buffer.into_inner())
+}
+
+// 4:2:0 は image crate の JpegEncoder が色差成分を間引いて書き込めるようになったら足す
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Subsampling {
+    Yuv444,
+}
+
+impl Subsampling {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "444" => Some(Subsampling::Yuv444),
+            _ => None,
+        }
+    }
+}
+
+// 透明な部分を背景色と合成して RGB にする
+fn flatten_alpha(
+    imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    background: (u8, u8, u8),
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (r, g, b) = background;
+    ImageBuffer::from_fn(imgbuf.width(), imgbuf.height(), |x, y| {
+        let mut px = Rgba([r, g, b, 255]);
+        let src = imgbuf.get_pixel(x, y);
+        blend_pixel(
+            &mut px,
+            (src.0[0], src.0[1], src.0[2]),
+            src.0[3] as f32 / 255.0,
+        );
+        Rgb([px.0[0], px.0[1], px.0[2]])
+    })
+}
+
+// pHYs などのチャンクを書き込むために png crate を直接使う
+fn encode_png(
+    imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    params: &Params,
+) -> image::ImageResult<Vec<u8>> {
+    let to_image_error = |e: png::EncodingError| {
+        image::ImageError::Encoding(image::error::EncodingError::new(
+            image::error::ImageFormatHint::Exact(image::ImageFormat::Png),
+            e,
+        ))
+    };
+
+    let mut buffer = vec![];
+    let mut encoder = png::Encoder::new(&mut buffer, imgbuf.width(), imgbuf.height());
+    let flattened = params
+        .strip_alpha
+        .then(|| flatten_alpha(imgbuf, params.background));
+    encoder.set_color(match flattened {
+        Some(_) => png::ColorType::Rgb,
+        None => png::ColorType::Rgba,
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    if let Some(dpi) = params.dpi {
+        // 1 inch = 0.0254 m
+        let ppm = (dpi as f32 / 0.0254).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: ppm,
+            yppu: ppm,
+            unit: png::Unit::Meter,
+        }));
+    }
+    if params.embed_meta {
+        // 日本語も入るので tEXt ではなく UTF-8 の iTXt を使う
+        encoder
+            .add_itxt_chunk("Title".to_string(), params.title.clone())
+            .map_err(to_image_error)?;
+        let description = match &params.columns {
+            Some((left, right)) => format!("{left}\n{right}"),
+            None if params.items.is_empty() => params.text.clone(),
+            None => params.items.join("\n"),
+        };
+        encoder
+            .add_itxt_chunk("Description".to_string(), description)
+            .map_err(to_image_error)?;
+        if let Some(author) = &params.author {
+            encoder
+                .add_itxt_chunk("Author".to_string(), author.clone())
+                .map_err(to_image_error)?;
+        }
+    }
+    let mut writer = encoder.write_header().map_err(to_image_error)?;
+    writer
+        .write_image_data(match &flattened {
+            Some(flattened) => flattened.as_raw(),
+            None => imgbuf.as_raw(),
+        })
+        .map_err(to_image_error)?;
+    writer.finish().map_err(to_image_error)?;
+    Ok(buffer)
+}
+
+fn render_text<F: Font, C: Canvas>(
+    font: &F,
+    font_scale: PxScale,
+    canvas: &mut C,
+    text: &str,
+    paint: TextPaint,
+    text_position: Point,
+    options: LayoutOptions,
+) {
+    let mut glyphs = Vec::new();
+    layout_text(font, font_scale, text_position, options, text, &mut glyphs);
+
+    canvas.draw_glyphs(font, glyphs, paint);
+}
+
+fn measure_text<F: Font>(
+    font: &F,
+    font_scale: PxScale,
+    text: &str,
+    text_position: Point,
+    options: LayoutOptions,
+) -> Option<Rect> {
+    let mut glyphs = Vec::new();
+    let font_scale = layout_text(font, font_scale, text_position, options, text, &mut glyphs);
+
+    glyph_bounds(font.as_scaled(font_scale), &glyphs)
+}
+
+// options.max_lines を超える場合は options.overflow に従って収め、実際に使った大きさを返す
+fn layout_text<F: Font>(
+    font: &F,
+    font_scale: PxScale,
+    position: Point,
+    options: LayoutOptions,
+    text: &str,
+    target: &mut Vec<Glyph>,
+) -> PxScale {
+    let mut glyphs = Vec::new();
+    layout_paragraph(
+        font.as_scaled(font_scale),
+        position,
+        options,
+        text,
+        &mut glyphs,
+    );
+    let Some(max_lines) = options.max_lines else {
+        target.extend(glyphs);
+        return font_scale;
+    };
+    if count_lines(&glyphs) <= max_lines {
+        target.extend(glyphs);
+        return font_scale;
+    }
+
+    match options.overflow {
+        Overflow::Shrink if font_scale.y > MIN_TEXT_FONT_SIZE => {
+            let size = (font_scale.y - 2.0).max(MIN_TEXT_FONT_SIZE);
+            layout_text(font, PxScale::from(size), position, options, text, target)
+        }
+        Overflow::Ellipsis => {
+            // layout_paragraph は制御文字以外の文字ごとに 1 つ glyph を作るので、収まった glyph の数だけ文字を残す
+            let chars: Vec<(usize, char)> = text
+                .char_indices()
+                .filter(|(_, c)| !c.is_control())
+                .collect();
+            let mut kept = kept_glyphs(&glyphs, max_lines);
+            loop {
+                let end = chars.get(kept).map_or(text.len(), |&(i, _)| i);
+                let truncated = format!("{}…", text[..end].trim_end());
+                let mut glyphs = Vec::new();
+                layout_paragraph(
+                    font.as_scaled(font_scale),
+                    position,
+                    options,
+                    &truncated,
+                    &mut glyphs,
+                );
+                if kept == 0 || count_lines(&glyphs) <= max_lines {
+                    target.extend(glyphs);
+                    return font_scale;
+                }
+                kept -= 1;
+            }
+        }
+        // shrink で最小の大きさでも収まらない場合は clip と同じく切り捨てる
+        Overflow::Shrink | Overflow::Clip => {
+            glyphs.truncate(kept_glyphs(&glyphs, max_lines));
+            target.extend(glyphs);
+            font_scale
+        }
+    }
+}
+
+// 先頭から max_lines 行目までに配置された glyph の数
+fn kept_glyphs(glyphs: &[Glyph], max_lines: usize) -> usize {
+    let mut lines = 0;
+    let mut baseline = None;
+    glyphs
+        .iter()
+        .take_while(|glyph| {
+            if baseline != Some(glyph.position.y) {
+                baseline = Some(glyph.position.y);
+                lines += 1;
+            }
+            lines <= max_lines
+        })
+        .count()
+}
+
+fn glyph_bounds<F, SF>(font: SF, glyphs: &[Glyph]) -> Option<Rect>
+where
+    F: Font,
+    SF: ScaleFont<F>,
+{
+    glyphs.iter().fold(None, |bounds: Option<Rect>, glyph| {
+        let glyph_bounds = Rect {
+            min: point(glyph.position.x, glyph.position.y - font.ascent()),
+            max: point(
+                glyph.position.x + font.h_advance(glyph.id),
+                glyph.position.y - font.descent(),
+            ),
+        };
+        union_bounds(bounds, Some(glyph_bounds))
+    })
+}
+
+fn union_bounds(a: Option<Rect>, b: Option<Rect>) -> Option<Rect> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(Rect {
+            min: point(a.min.x.min(b.min.x), a.min.y.min(b.min.y)),
+            max: point(a.max.x.max(b.max.x), a.max.y.max(b.max.y)),
+        }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn render_glyphs<F: Font>(
+    font: &F,
+    glyphs: Vec<Glyph>,
+    mut imgbuf: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    paint: TextPaint,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    // 拡大して描画する場合も輪郭から描くので文字はぼやけない
+    let outlines: Vec<_> = glyphs
+        .into_iter()
+        .filter_map(|glyph| {
+            font.outline_glyph(Glyph {
+                scale: PxScale {
+                    x: glyph.scale.x * paint.scale,
+                    y: glyph.scale.y * paint.scale,
+                },
+                position: point(
+                    glyph.position.x * paint.scale,
+                    glyph.position.y * paint.scale,
+                ),
+                ..glyph
+            })
+        })
+        .collect();
+    if let Some(shadow) = paint.shadow {
+        render_shadow(&outlines, &mut imgbuf, shadow, paint.scale);
+    }
+    let fill = !paint.stroke.is_some_and(|stroke| stroke.hollow);
+    // グラデーションは描く文字全体の範囲に対する位置で色を決める
+    let (left, right) = outlines.iter().map(|outlined| outlined.px_bounds()).fold(
+        (f32::INFINITY, f32::NEG_INFINITY),
+        |(left, right), bounds| (left.min(bounds.min.x), right.max(bounds.max.x)),
+    );
+    for outlined in outlines.iter().filter(|_| fill) {
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, v| {
+            // キャンバスからはみ出した部分は描画しない
+            let x = x as i32 + bounds.min.x as i32;
+            let y = y as i32 + bounds.min.y as i32;
+            if x < 0 || y < 0 || x >= imgbuf.width() as i32 || y >= imgbuf.height() as i32 {
+                return;
+            }
+            let px = imgbuf.get_pixel_mut(x as u32, y as u32);
+            let v = paint.coverage(v);
+            let color = match paint.gradient {
+                Some(gradient) => gradient.at((x as f32 + 0.5 - left) / (right - left)),
+                None => paint.color,
+            };
+            if paint.gamma {
+                blend_pixel_linear(px, color, v);
+            } else {
+                blend_pixel(px, color, v);
+            }
+        });
+    }
+    if let Some(stroke) = paint.stroke {
+        render_stroke(&outlines, &mut imgbuf, stroke, paint);
+    }
+    imgbuf
+}
+
+// 文字を 1 枚のタイルに描いておき、キャンバスの各ピクセルを傾けた座標に戻してタイルから補間する
+fn render_tiled<F: Font>(
+    font: &F,
+    glyphs: Vec<Glyph>,
+    imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    tile: (f32, f32),
+    paint: TextPaint,
+    opacity: f32,
+) {
+    let tile_width = (tile.0 * paint.scale).ceil().max(1.0);
+    let tile_height = (tile.1 * paint.scale).ceil().max(1.0);
+    let mut mask = image::GrayImage::new(tile_width as u32, tile_height as u32);
+    for glyph in glyphs {
+        let Some(outlined) = font.outline_glyph(Glyph {
+            scale: PxScale {
+                x: glyph.scale.x * paint.scale,
+                y: glyph.scale.y * paint.scale,
+            },
+            position: point(
+                glyph.position.x * paint.scale,
+                glyph.position.y * paint.scale,
+            ),
+            ..glyph
+        }) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|x, y, v| {
+            let x = x as i32 + bounds.min.x as i32;
+            let y = y as i32 + bounds.min.y as i32;
+            if x < 0 || y < 0 || x >= mask.width() as i32 || y >= mask.height() as i32 {
+                return;
+            }
+            let px = mask.get_pixel_mut(x as u32, y as u32);
+            px.0[0] = px.0[0].saturating_add((v.clamp(0.0, 1.0) * 255.0) as u8);
+        });
+    }
+    // タイルの端は反対側とつながっているものとして補間する
+    let sample = |u: f32, v: f32| {
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x0, y0) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x0, v - y0);
+        let at = |x: f32, y: f32| {
+            let x = x.rem_euclid(tile_width) as u32;
+            let y = y.rem_euclid(tile_height) as u32;
+            mask.get_pixel(x, y).0[0] as f32 / 255.0
+        };
+        let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1.0, y0) * fx;
+        let bottom = at(x0, y0 + 1.0) * (1.0 - fx) + at(x0 + 1.0, y0 + 1.0) * fx;
+        top * (1.0 - fy) + bottom * fy
+    };
+    let (sin, cos) = WATERMARK_ANGLE.to_radians().sin_cos();
+    for (x, y, px) in imgbuf.enumerate_pixels_mut() {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        // 文字の進む向き (cos, -sin) と、それに直交する下向き (sin, cos) の成分に分ける
+        let u = x * cos - y * sin;
+        let v = x * sin + y * cos;
+        let row = (v / tile_height).floor();
+        let coverage = paint.coverage(sample(u + row * tile_width / 2.0, v));
+        if coverage > 0.0 {
+            blend_pixel(px, paint.color, coverage * opacity);
+        }
+    }
+}
+
+// 透かしは本文のフォントで 1 行に並べ、文字の大きさの分だけ間を空けて繰り返す
+fn render_watermark<F: Font, C: Canvas>(font: &F, canvas: &mut C, text: &str, params: &Params) {
+    let scaled = font.as_scaled(PxScale::from(WATERMARK_FONT_SIZE));
+    let gap = WATERMARK_FONT_SIZE;
+    let mut glyphs = Vec::new();
+    let caret = layout_paragraph(
+        scaled,
+        point(gap / 2.0, gap / 2.0),
+        LayoutOptions::default(),
+        text,
+        &mut glyphs,
+    );
+    let tile = (caret.x + gap / 2.0, scaled.height() + gap);
+    canvas.draw_tiled(
+        font,
+        glyphs,
+        tile,
+        TextPaint {
+            shadow: None,
+            stroke: None,
+            ..params.paint(params.text_color)
+        },
+        WATERMARK_OPACITY,
+    );
+}
+
+// 文字の範囲だけのカバレッジを padding の余白を付けて描き、左上の座標と一緒に返す
+fn coverage_mask(
+    outlines: &[ab_glyph::OutlinedGlyph],
+    padding: i32,
+) -> Option<(image::GrayImage, i32, i32)> {
+    let bounds = outlines
+        .iter()
+        .map(|outlined| outlined.px_bounds())
+        .reduce(|a, b| Rect {
+            min: point(a.min.x.min(b.min.x), a.min.y.min(b.min.y)),
+            max: point(a.max.x.max(b.max.x), a.max.y.max(b.max.y)),
+        })?;
+    let origin_x = bounds.min.x as i32 - padding;
+    let origin_y = bounds.min.y as i32 - padding;
+    let width = (bounds.width().ceil() as i32 + padding * 2).max(1) as u32;
+    let height = (bounds.height().ceil() as i32 + padding * 2).max(1) as u32;
+    let mut alpha = image::GrayImage::new(width, height);
+    for outlined in outlines {
+        let glyph_bounds = outlined.px_bounds();
+        outlined.draw(|x, y, v| {
+            let x = x as i32 + glyph_bounds.min.x as i32 - origin_x;
+            let y = y as i32 + glyph_bounds.min.y as i32 - origin_y;
+            if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                return;
+            }
+            let px = alpha.get_pixel_mut(x as u32, y as u32);
+            px.0[0] = px.0[0].saturating_add((v.clamp(0.0, 1.0) * 255.0) as u8);
+        });
+    }
+    Some((alpha, origin_x, origin_y))
+}
+
+// 半径 radius の円の中で最大 (膨張) か最小 (収縮) を取る
+fn morphology(alpha: &image::GrayImage, radius: i32, dilate: bool) -> image::GrayImage {
+    if radius == 0 {
+        return alpha.clone();
+    }
+    let offsets: Vec<(i32, i32)> = (-radius..=radius)
+        .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| dx * dx + dy * dy <= radius * radius)
+        .collect();
+    let (width, height) = (alpha.width() as i32, alpha.height() as i32);
+    image::GrayImage::from_fn(alpha.width(), alpha.height(), |x, y| {
+        let values = offsets.iter().map(|(dx, dy)| {
+            let (x, y) = (x as i32 + dx, y as i32 + dy);
+            if x < 0 || y < 0 || x >= width || y >= height {
+                0
+            } else {
+                alpha.get_pixel(x as u32, y as u32).0[0]
+            }
+        });
+        let value = if dilate { values.max() } else { values.min() };
+        image::Luma([value.unwrap_or(0)])
+    })
+}
+
+// 輪郭から外側に ceil(w/2)、内側に floor(w/2) の帯を線として描く
+fn render_stroke(
+    outlines: &[ab_glyph::OutlinedGlyph],
+    imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    stroke: Stroke,
+    paint: TextPaint,
+) {
+    let width = (stroke.width * paint.scale).round().max(1.0) as i32;
+    let (outer, inner) = ((width + 1) / 2, width / 2);
+    let Some((alpha, origin_x, origin_y)) = coverage_mask(outlines, outer + 1) else {
+        return;
+    };
+    let dilated = morphology(&alpha, outer, true);
+    let eroded = morphology(&alpha, inner, false);
+    let color = stroke.color.unwrap_or(paint.color);
+    for ((x, y, outside), inside) in dilated.enumerate_pixels().zip(eroded.pixels()) {
+        let x = x as i32 + origin_x;
+        let y = y as i32 + origin_y;
+        if outside.0[0] == 0
+            || x < 0
+            || y < 0
+            || x >= imgbuf.width() as i32
+            || y >= imgbuf.height() as i32
+        {
+            continue;
+        }
+        let v = paint.coverage(outside.0[0] as f32 / 255.0 * (1.0 - inside.0[0] as f32 / 255.0));
+        let px = imgbuf.get_pixel_mut(x as u32, y as u32);
+        if paint.gamma {
+            blend_pixel_linear(px, color, v);
+        } else {
+            blend_pixel(px, color, v);
+        }
+    }
+}
+
+// 文字の範囲だけのアルファを描いてぼかしてから、影の色で合成する
+fn render_shadow(
+    outlines: &[ab_glyph::OutlinedGlyph],
+    imgbuf: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
+    shadow: Shadow,
+    scale: f32,
+) {
+    let sigma = shadow.blur * scale;
+    // ぼかしで広がる分の余白を取る
+    let padding = (sigma * 3.0).ceil() as i32;
+    let Some((mut alpha, origin_x, origin_y)) = coverage_mask(outlines, padding) else {
+        return;
+    };
+    if sigma > 0.0 {
+        alpha = image::imageops::blur(&alpha, sigma);
+    }
+    let offset_x = origin_x + (shadow.offset.x * scale).round() as i32;
+    let offset_y = origin_y + (shadow.offset.y * scale).round() as i32;
+    for (x, y, v) in alpha.enumerate_pixels() {
+        let x = x as i32 + offset_x;
+        let y = y as i32 + offset_y;
+        if v.0[0] == 0
+            || x < 0
+            || y < 0
+            || x >= imgbuf.width() as i32
+            || y >= imgbuf.height() as i32
+        {
+            continue;
+        }
+        blend_pixel(
+            imgbuf.get_pixel_mut(x as u32, y as u32),
+            shadow.color,
+            v.0[0] as f32 / 255.0,
+        );
+    }
+}
+
+// レイアウトの設定が増えても呼び出し側を変えずに済むようにまとめて渡す
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct LayoutOptions {
+    // この幅を超えたら折り返す
+    max_width: f32,
+    // 行送りの倍率
+    line_spacing: f32,
+    // 文字ごとに追加する間隔
+    tracking: Length,
+    // 行の間に追加する間隔
+    leading: Length,
+    // 渡した位置の y を文字の上端 (ascent) とベースラインのどちらとして扱うか
+    anchor: Anchor,
+    // 自動で折り返した行の単語の間隔を広げて max_width に揃えるかどうか
+    align: Align,
+    // layout_text でこの行数を超えたら overflow に従って収める
+    max_lines: Option<usize>,
+    overflow: Overflow,
+    // 数字をすべて一番広い数字の幅で送って桁を揃える
+    tabular: bool,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        LayoutOptions {
+            max_width: f32::INFINITY,
+            line_spacing: 1.0,
+            tracking: Length::Px(0.0),
+            leading: Length::Px(0.0),
+            anchor: Anchor::Top,
+            align: Align::Left,
+            max_lines: None,
+            overflow: Overflow::Ellipsis,
+            tabular: false,
+        }
+    }
+}
+
+impl LayoutOptions {
+    fn with_max_width(self, max_width: f32) -> Self {
+        LayoutOptions { max_width, ..self }
+    }
+
+    fn with_max_lines(self, max_lines: Option<usize>, overflow: Overflow) -> Self {
+        LayoutOptions {
+            max_lines,
+            overflow,
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Anchor {
+    Top,
+    Baseline,
+}
+
+impl Anchor {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "top" => Some(Anchor::Top),
+            "baseline" => Some(Anchor::Baseline),
+            _ => None,
+        }
+    }
+
+    // 1 行目のベースラインを position.y からどれだけ下げるか
+    fn offset(self, ascent: f32) -> f32 {
+        match self {
+            Anchor::Top => ascent,
+            Anchor::Baseline => 0.0,
+        }
+    }
+}
+
+// 行頭禁則 (句読点、閉じ括弧、長音記号、小書きの仮名など)
+fn is_line_start_forbidden(c: char) -> bool {
+    matches!(
+        c,
+        '、' | '。'
+            | '，'
+            | '．'
+            | '・'
+            | '：'
+            | '；'
+            | '？'
+            | '！'
+            | '‼'
+            | '⁇'
+            | '゛'
+            | '゜'
+            | 'ゝ'
+            | 'ゞ'
+            | 'ヽ'
+            | 'ヾ'
+            | '々'
+            | '〻'
+            | 'ー'
+            | '‥'
+            | '…'
+            | '）'
+            | '］'
+            | '｝'
+            | '」'
+            | '』'
+            | '】'
+            | '〕'
+            | '〉'
+            | '》'
+            | '〗'
+            | '〙'
+            | '〟'
+            | '’'
+            | '”'
+            | '｠'
+            | 'ぁ'
+            | 'ぃ'
+            | 'ぅ'
+            | 'ぇ'
+            | 'ぉ'
+            | 'っ'
+            | 'ゃ'
+            | 'ゅ'
+            | 'ょ'
+            | 'ゎ'
+            | 'ゕ'
+            | 'ゖ'
+            | 'ァ'
+            | 'ィ'
+            | 'ゥ'
+            | 'ェ'
+            | 'ォ'
+            | 'ッ'
+            | 'ャ'
+            | 'ュ'
+            | 'ョ'
+            | 'ヮ'
+            | 'ヵ'
+            | 'ヶ'
+            | '｡'
+            | '｣'
+            | '､'
+            | '･'
+            | 'ｰ'
+            | 'ｧ'..='ｯ'
+    )
+}
+
+// 行末禁則 (開き括弧)
+fn is_line_end_forbidden(c: char) -> bool {
+    matches!(
+        c,
+        '（' | '［'
+            | '｛'
+            | '「'
+            | '『'
+            | '【'
+            | '〔'
+            | '〈'
+            | '《'
+            | '〖'
+            | '〘'
+            | '〝'
+            | '‘'
+            | '“'
+            | '｟'
+            | '｢'
+    )
+}
+
+// 前の文字に重ねて描く結合文字 (ベトナム語の声調記号や濁点など)
+fn is_combining_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{3099}'..='\u{309A}'
+            | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
+// 結合文字の輪郭の中心を基底文字の送り幅の中心に合わせる
+// (フォントによって送り幅が 0 で左にはみ出すものと、送り幅を持つものがある)
+fn combining_mark_x<F, SF>(font: &SF, mark: GlyphId, base: &Glyph) -> f32
+where
+    F: Font,
+    SF: ScaleFont<F>,
+{
+    let base_center = base.position.x + font.h_advance(base.id) / 2.0;
+    match font.font().outline(mark) {
+        Some(outline) => {
+            let center = (outline.bounds.min.x + outline.bounds.max.x) / 2.0;
+            base_center - center * font.h_scale_factor()
+        }
+        None => base_center - font.h_advance(mark) / 2.0,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Align {
+    Left,
+    Justify,
+}
+
+impl Align {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "left" => Some(Align::Left),
+            "justify" => Some(Align::Justify),
+            _ => None,
+        }
+    }
+}
+
+// 行の先頭と末尾の空白を除いた単語の間の空白に extra を等分して足す
+// 空白のない行 (日本語など) や、はみ出した行 (extra が負) は詰めずにそのままにする
+fn justify_line(glyphs: &mut [Glyph], spaces: &[bool], extra: f32) {
+    if extra <= 0.0 {
+        return;
+    }
+    let (Some(first), Some(last)) = (
+        spaces.iter().position(|&space| !space),
+        spaces.iter().rposition(|&space| !space),
+    ) else {
+        return;
+    };
+    let gaps = spaces[first..=last].iter().filter(|&&space| space).count();
+    if gaps == 0 {
+        return;
+    }
+    let per_gap = extra / gaps as f32;
+    let mut shift = 0.0;
+    for (glyph, &space) in glyphs[first..=last].iter_mut().zip(&spaces[first..=last]) {
+        if space {
+            shift += per_gap;
+        }
+        glyph.position.x += shift;
+    }
+}
+
+// 最後に配置した文字の次の位置 (ベースライン上) を返す
+fn layout_paragraph<F, SF>(
+    font: SF,
+    position: Point,
+    options: LayoutOptions,
+    text: &str,
+    target: &mut Vec<Glyph>,
+) -> Point
+where
+    F: Font,
+    SF: ScaleFont<F>,
+{
+    layout_runs(&[(font, text)], position, options, target)
+}
+
+// 大きさの違う文字列を同じ行に並べ、一番大きい run の ascent でベースラインを揃える
+// 折り返した行の高さも一番大きい run に合わせる
+fn layout_runs<F, SF>(
+    runs: &[(SF, &str)],
+    position: Point,
+    options: LayoutOptions,
+    target: &mut Vec<Glyph>,
+) -> Point
+where
+    F: Font,
+    SF: ScaleFont<F>,
+{
+    let ascent = runs
+        .iter()
+        .map(|(font, _)| font.ascent())
+        .fold(0.0, f32::max);
+    let v_advance = runs
+        .iter()
+        .map(|(font, _)| {
+            (font.height() + font.line_gap()) * options.line_spacing
+                + options.leading.resolve(font.scale().y)
+        })
+        .fold(0.0, f32::max);
+    let mut caret = point(position.x, position.y + options.anchor.offset(ascent));
+    // justify で使うため target[start..] の文字が空白かどうかを並べておく
+    let start = target.len();
+    let mut spaces = Vec::new();
+    let mut line_start = start;
+    // 行頭に置けない文字が続くときは折り返しをその文字のあとまで延ばす
+    let mut pending_wrap = false;
+    for (font, text) in runs {
+        // 大きさが違う文字の間ではカーニングしない
+        let mut last_glyph: Option<Glyph> = None;
+        // ab_glyph では OpenType の tnum を使えないので、数字の幅を揃えて送る
+        let digit_advance = options.tabular.then(|| {
+            ('0'..='9')
+                .map(|digit| font.h_advance(font.glyph_id(digit)))
+                .fold(0.0, f32::max)
+        });
+        let mut last_digit = false;
+        // 結合文字を重ねる基底文字 (折り返しで動いても追えるように target の位置で持つ)
+        let mut base: Option<usize> = None;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c.is_control() {
+                if c == '\n' {
+                    caret = point(position.x, caret.y + v_advance);
+                    last_glyph = None;
+                    base = None;
+                    line_start = target.len();
+                    pending_wrap = false;
+                }
+                continue;
+            }
+            let mut glyph = font.scaled_glyph(c);
+            let tracking = options.tracking.resolve(font.scale().y);
+            // 結合文字はキャレットを進めず、基底文字の上に置く
+            let mark = base.is_some() && is_combining_mark(c);
+            if let Some(base) = base.filter(|_| mark) {
+                let base = &target[base];
+                glyph.position = point(combining_mark_x(font, glyph.id, base), base.position.y);
+            } else {
+                let advance = font.h_advance(glyph.id);
+                let digit = digit_advance.filter(|_| c.is_ascii_digit());
+                if let Some(previous) = last_glyph.take() {
+                    if digit.is_none() && !last_digit {
+                        caret.x += font.kern(previous.id, glyph.id);
+                    }
+                }
+                last_digit = digit.is_some();
+                // 数字は揃えた幅の中央に置く
+                glyph.position = point(
+                    caret.x + digit.map_or(0.0, |cell| (cell - advance) / 2.0),
+                    caret.y,
+                );
+
+                last_glyph = Some(glyph.clone());
+                caret.x += digit.unwrap_or(advance) + tracking;
+                base = Some(target.len());
+            }
+
+            target.push(glyph);
+            spaces.push(c.is_whitespace());
+
+            let overflow = !mark && !c.is_whitespace() && caret.x > position.x + options.max_width;
+            // justify では単語の途中で切らず、行の最後の空白で折り返して続きの単語を次の行に送る
+            let word_break = (overflow && options.align == Align::Justify)
+                .then(|| {
+                    let line = &spaces[line_start - start..];
+                    let first = line.iter().position(|&space| !space)?;
+                    let space = line
+                        .iter()
+                        .rposition(|&space| space)
+                        .filter(|&i| i > first)?;
+                    let end = line[..space].iter().rposition(|&space| !space)? + 1;
+                    Some((line_start + end, line_start + space + 1))
+                })
+                .flatten();
+            if let Some((line_end, next_start)) = word_break {
+                let extra = position.x + options.max_width - target[line_end].position.x;
+                let before = target[line_end - 1].position.x;
+                justify_line(
+                    &mut target[line_start..line_end],
+                    &spaces[line_start - start..line_end - start],
+                    extra,
+                );
+                // 行末に残る空白も広げた行の後ろに付ける
+                let shift = target[line_end - 1].position.x - before;
+                for glyph in &mut target[line_end..next_start] {
+                    glyph.position.x += shift;
+                }
+                let dx = position.x - target[next_start].position.x;
+                for glyph in &mut target[next_start..] {
+                    glyph.position.x += dx;
+                    glyph.position.y += v_advance;
+                }
+                caret = point(caret.x + dx, caret.y + v_advance);
+                line_start = next_start;
+                pending_wrap = false;
+                continue;
+            }
+            if pending_wrap || overflow {
+                // 禁則処理: 句読点や閉じ括弧は前の行の末尾にはみ出させ、開き括弧は次の行に送る
+                // 結合文字も基底文字から離さない
+                if chars
+                    .peek()
+                    .is_some_and(|&next| is_line_start_forbidden(next) || is_combining_mark(next))
+                {
+                    pending_wrap = true;
+                    continue;
+                }
+                pending_wrap = false;
+                let carried = is_line_end_forbidden(c) && target.len() - line_start > 1;
+                let line_end = if carried {
+                    target.len() - 1
+                } else {
+                    target.len()
+                };
+                if options.align == Align::Justify {
+                    let end_x = if carried {
+                        target[line_end].position.x
+                    } else {
+                        caret.x - tracking
+                    };
+                    justify_line(
+                        &mut target[line_start..line_end],
+                        &spaces[line_start - start..line_end - start],
+                        position.x + options.max_width - end_x,
+                    );
+                }
+                caret = point(position.x, caret.y + v_advance);
+                if carried {
+                    let glyph = &mut target[line_end];
+                    glyph.position = caret;
+                    caret.x = position.x + font.h_advance(glyph.id) + tracking;
+                } else {
+                    last_glyph = None;
+                }
+                line_start = line_end;
+            }
+        }
+    }
+    caret
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ListStyle {
+    Bullet,
+    Number,
+}
+
+impl ListStyle {
+    fn from_param(s: &str) -> Option<Self> {
+        match s {
+            "bullet" => Some(ListStyle::Bullet),
+            "number" => Some(ListStyle::Number),
+            _ => None,
+        }
+    }
+
+    fn marker(self, index: usize) -> String {
+        match self {
+            ListStyle::Bullet => "•".to_string(),
+            ListStyle::Number => format!("{}.", index + 1),
+        }
+    }
+}
+
+fn layout_list<F, SF>(
+    font: SF,
+    position: Point,
+    options: LayoutOptions,
+    items: &[String],
+    style: ListStyle,
+    target: &mut Vec<Glyph>,
+) where
+    F: Font,
+    SF: ScaleFont<F> + Copy,
+{
+    let v_advance = (font.height() + font.line_gap()) * options.line_spacing
+        + options.leading.resolve(font.scale().y);
+    let markers: Vec<String> = (0..items.len()).map(|i| style.marker(i)).collect();
+    // 折り返した行が項目の先頭に揃うように、一番幅の広いマーカーの分だけ字下げする
+    let indent = markers
+        .iter()
+        .map(|marker| {
+            layout_paragraph(
+                font,
+                point(0.0, 0.0),
+                options.with_max_width(f32::INFINITY),
+                marker,
+                &mut vec![],
+            )
+            .x
+        })
+        .fold(0.0, f32::max)
+        + font.h_advance(font.glyph_id(' '));
+
+    let mut y = position.y;
+    for (marker, item) in markers.iter().zip(items) {
+        layout_paragraph(
+            font,
+            point(position.x, y),
+            options.with_max_width(f32::INFINITY),
+            marker,
+            target,
+        );
+        let caret = layout_paragraph(
+            font,
+            point(position.x + indent, y),
+            options.with_max_width(options.max_width - indent),
+            item,
+            target,
+        );
+        y = caret.y - options.anchor.offset(font.ascent()) + v_advance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ab_glyph::FontRef;
+
+    const TEST_FONT: &[u8] = include_bytes!("../testdata/DejaVuSans.ttf");
+
+    fn test_font() -> FontRef<'static> {
+        FontRef::try_from_slice(TEST_FONT).unwrap()
+    }
+
+    fn test_input(extra: &[(&str, &str)]) -> Vec<(String, String)> {
+        [("title", "title"), ("text", "text"), ("author", "author")]
+            .iter()
+            .chain(extra)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn test_params() -> Params {
+        parse_params(&test_input(&[]), Variant::default()).unwrap()
+    }
+
+    fn layout(text: &str, max_width: f32) -> Vec<Glyph> {
+        let font = test_font();
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            font.as_scaled(PxScale::from(60.0)),
+            point(80.0, 80.0),
+            LayoutOptions::default().with_max_width(max_width),
+            text,
+            &mut glyphs,
+        );
+        glyphs
+    }
+
+    #[test]
+    fn layout_paragraph_skips_control_characters() {
+        let glyphs = layout("ab\ncd\t", 1000.0);
+        assert_eq!(glyphs.len(), 4);
+    }
+
+    #[test]
+    fn layout_paragraph_starts_at_position() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let glyphs = layout("abc", 1000.0);
+        assert_eq!(glyphs[0].position.x, 80.0);
+        assert_eq!(glyphs[0].position.y, 80.0 + scaled.ascent());
+        assert!(glyphs[1].position.x > glyphs[0].position.x);
+        assert_eq!(glyphs[1].position.y, glyphs[0].position.y);
+    }
+
+    #[test]
+    fn layout_paragraph_breaks_on_newline() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let glyphs = layout("a\nb", 1000.0);
+        assert_eq!(glyphs[1].position.x, 80.0);
+        assert_eq!(
+            glyphs[1].position.y - glyphs[0].position.y,
+            scaled.height() + scaled.line_gap()
+        );
+    }
+
+    #[test]
+    fn layout_options_adjust_spacing() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let options = LayoutOptions {
+            line_spacing: 1.5,
+            tracking: Length::Px(10.0),
+            ..LayoutOptions::default()
+        };
+        let mut glyphs = Vec::new();
+        layout_paragraph(scaled, point(80.0, 80.0), options, "ab\nc", &mut glyphs);
+        assert_eq!(
+            glyphs[1].position.x - glyphs[0].position.x,
+            scaled.h_advance(glyphs[0].id) + scaled.kern(glyphs[0].id, glyphs[1].id) + 10.0
+        );
+        assert_eq!(
+            glyphs[2].position.y - glyphs[0].position.y,
+            (scaled.height() + scaled.line_gap()) * 1.5
+        );
+    }
+
+    #[test]
+    fn layout_options_resolve_em_against_font_size() {
+        let font = test_font();
+        let options = LayoutOptions {
+            tracking: Length::Em(0.5),
+            leading: Length::Em(1.0),
+            ..LayoutOptions::default()
+        };
+        for size in [30.0, 60.0] {
+            let scaled = font.as_scaled(PxScale::from(size));
+            let mut glyphs = Vec::new();
+            layout_paragraph(scaled, point(80.0, 80.0), options, "ab\nc", &mut glyphs);
+            assert_eq!(
+                glyphs[1].position.x - glyphs[0].position.x,
+                scaled.h_advance(glyphs[0].id)
+                    + scaled.kern(glyphs[0].id, glyphs[1].id)
+                    + size / 2.0
+            );
+            assert_eq!(
+                glyphs[2].position.y - glyphs[0].position.y,
+                scaled.height() + scaled.line_gap() + size
+            );
+        }
+    }
+
+    #[test]
+    fn parse_params_reads_lengths() {
+        assert_eq!(Length::from_param("1.5em"), Some(Length::Em(1.5)));
+        assert_eq!(Length::from_param("12px"), Some(Length::Px(12.0)));
+        assert_eq!(Length::from_param("12"), Some(Length::Px(12.0)));
+        assert_eq!(Length::from_param("em"), None);
+        assert_eq!(Length::from_param("infem"), None);
+
+        let params = parse_params(
+            &test_input(&[("padding", "1.5em"), ("tracking", "0.1em")]),
+            Variant::default(),
+        )
+        .unwrap();
+        assert_eq!(params.margin_left, 1.5 * TEXT_FONT_SIZE);
+        assert_eq!(params.margin_right, 1.5 * TEXT_FONT_SIZE);
+        assert_eq!(params.tracking, Length::Em(0.1));
+        assert_eq!(params.leading, Length::Px(0.0));
+    }
+
+    #[test]
+    fn layout_paragraph_justifies_wrapped_lines() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let layout = |align| {
+            let mut glyphs = Vec::new();
+            layout_paragraph(
+                scaled,
+                point(80.0, 80.0),
+                LayoutOptions {
+                    align,
+                    ..LayoutOptions::default().with_max_width(500.0)
+                },
+                "aa bb cc dd ee ff gg\nhh ii",
+                &mut glyphs,
+            );
+            glyphs
+        };
+        let left = layout(Align::Left);
+        let justified = layout(Align::Justify);
+        assert_eq!(left.len(), justified.len());
+        // 1 行目の末尾の文字の右端が max_width に揃う
+        let first_line_end = |glyphs: &[Glyph]| {
+            // 折り返した空白は行末に残るので数えない
+            let last = glyphs
+                .iter()
+                .rfind(|glyph| {
+                    glyph.position.y == glyphs[0].position.y && glyph.id != scaled.glyph_id(' ')
+                })
+                .unwrap();
+            last.position.x + scaled.h_advance(last.id)
+        };
+        assert!((first_line_end(&justified) - 580.0).abs() < 0.01);
+        assert_ne!(first_line_end(&left), first_line_end(&justified));
+        // 行頭と最後の行は動かさない
+        assert_eq!(justified[0].position, left[0].position);
+        assert_eq!(
+            justified.last().unwrap().position,
+            left.last().unwrap().position
+        );
+    }
+
+    #[test]
+    fn layout_paragraph_justify_does_not_overlap_words() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let text = "lorem ipsum dolor sit amet consectetur adipiscing";
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            scaled,
+            point(80.0, 80.0),
+            LayoutOptions {
+                align: Align::Justify,
+                ..LayoutOptions::default().with_max_width(420.0)
+            },
+            text,
+            &mut glyphs,
+        );
+        let chars: Vec<char> = text.chars().collect();
+        assert_eq!(glyphs.len(), chars.len());
+        assert!(glyphs.last().unwrap().position.y > glyphs[0].position.y);
+        let space_advance = scaled.h_advance(scaled.glyph_id(' '));
+        for (i, pair) in glyphs.windows(2).enumerate() {
+            let (glyph, next) = (&pair[0], &pair[1]);
+            if next.position.y != glyph.position.y {
+                // 単語の途中では折り返さない
+                assert!(chars[i] == ' ' || chars[i + 1] == ' ', "broken at {i}");
+                continue;
+            }
+            // 同じ行の文字は重ならず、単語の間は空白の幅より狭くならない
+            let advance = scaled.h_advance(glyph.id) + scaled.kern(glyph.id, next.id);
+            assert!(
+                next.position.x >= glyph.position.x + advance - 0.01,
+                "overlap at {i}"
+            );
+            if chars[i] == ' ' {
+                assert!(next.position.x - glyph.position.x >= space_advance - 0.01);
+            }
+            if chars[i + 1] != ' ' {
+                assert!(next.position.x + scaled.h_advance(next.id) <= 500.0 + 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_paragraph_applies_kinsoku() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let advance = scaled.h_advance(scaled.glyph_id('a'));
+        let layout = |text: &str| {
+            let mut glyphs = Vec::new();
+            layout_paragraph(
+                scaled,
+                point(0.0, 80.0),
+                LayoutOptions::default().with_max_width(advance * 2.5),
+                text,
+                &mut glyphs,
+            );
+            glyphs
+        };
+        // 3 文字目ではみ出して折り返すところで、次の 。 は前の行に残す
+        let glyphs = layout("aaa。b");
+        assert_eq!(glyphs[3].position.y, glyphs[0].position.y);
+        assert!(glyphs[4].position.y > glyphs[0].position.y);
+        assert_eq!(glyphs[4].position.x, 0.0);
+        // はみ出した開き括弧は次の行の先頭に送る
+        let glyphs = layout("aa「b");
+        assert!(glyphs[2].position.y > glyphs[0].position.y);
+        assert_eq!(glyphs[2].position.x, 0.0);
+        assert_eq!(glyphs[3].position.y, glyphs[2].position.y);
+        assert!(glyphs[3].position.x > 0.0);
+    }
+
+    #[test]
+    fn layout_paragraph_aligns_tabular_digits() {
+        // DejaVu Sans の数字は同じ幅なので、幅の違う文字で確かめる
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let layout = |text: &str, tabular: bool| {
+            let mut glyphs = Vec::new();
+            let caret = layout_paragraph(
+                scaled,
+                point(0.0, 80.0),
+                LayoutOptions {
+                    tabular,
+                    ..LayoutOptions::default()
+                },
+                text,
+                &mut glyphs,
+            );
+            (caret.x, glyphs)
+        };
+        let digit = scaled.h_advance(scaled.glyph_id('0'));
+        let (width, glyphs) = layout("1a2", true);
+        assert_eq!(glyphs[1].position.x, digit);
+        assert_eq!(width, digit * 2.0 + scaled.h_advance(scaled.glyph_id('a')));
+        assert_eq!(layout("12", true).0, layout("12", false).0);
+    }
+
+    #[test]
+    fn layout_paragraph_places_combining_marks_over_base() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let mut glyphs = Vec::new();
+        let caret = layout_paragraph(
+            scaled,
+            point(0.0, 80.0),
+            LayoutOptions::default(),
+            "e\u{0301}x",
+            &mut glyphs,
+        );
+        // 結合文字の輪郭は e の送り幅の中に収まり、キャレットは進まない
+        let mark = scaled.outline_glyph(glyphs[1].clone()).unwrap().px_bounds();
+        let center = (mark.min.x + mark.max.x) / 2.0;
+        assert!((center - scaled.h_advance(glyphs[0].id) / 2.0).abs() < 2.0);
+        assert_eq!(glyphs[1].position.y, glyphs[0].position.y);
+        assert_eq!(
+            caret.x,
+            layout_paragraph(
+                scaled,
+                point(0.0, 80.0),
+                LayoutOptions::default(),
+                "ex",
+                &mut vec![]
+            )
+            .x
+        );
+
+        // 折り返しても結合文字は基底文字と同じ行に残る
+        let advance = scaled.h_advance(scaled.glyph_id('a'));
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            scaled,
+            point(0.0, 80.0),
+            LayoutOptions::default().with_max_width(advance * 2.5),
+            "aaa\u{0301}b",
+            &mut glyphs,
+        );
+        assert_eq!(glyphs[3].position.y, glyphs[2].position.y);
+        assert!(glyphs[4].position.y > glyphs[2].position.y);
+        assert_eq!(glyphs[4].position.x, 0.0);
+    }
+
+    #[test]
+    fn layout_paragraph_anchors_first_line() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let layout = |anchor| {
+            let mut glyphs = Vec::new();
+            layout_paragraph(
+                scaled,
+                point(80.0, 80.0),
+                LayoutOptions {
+                    anchor,
+                    ..LayoutOptions::default()
+                },
+                "a\nb",
+                &mut glyphs,
+            );
+            glyphs
+        };
+        // ab_glyph は ascent - descent を PxScale に合わせるので、DejaVu Sans (1901 / -483) の ascent は約 47.8px
+        let top = layout(Anchor::Top);
+        assert!((top[0].position.y - (80.0 + 60.0 * 1901.0 / 2384.0)).abs() < 0.5);
+        assert_eq!(top[0].position.y, 80.0 + scaled.ascent());
+        let baseline = layout(Anchor::Baseline);
+        assert_eq!(baseline[0].position.y, 80.0);
+        assert_eq!(
+            baseline[1].position.y - baseline[0].position.y,
+            top[1].position.y - top[0].position.y
+        );
+
+        let mut items = Vec::new();
+        layout_list(
+            scaled,
+            point(80.0, 80.0),
+            LayoutOptions {
+                anchor: Anchor::Baseline,
+                ..LayoutOptions::default()
+            },
+            &["a".to_string(), "b".to_string()],
+            ListStyle::Bullet,
+            &mut items,
+        );
+        assert_eq!(items[0].position.y, 80.0);
+        assert_eq!(
+            items[2].position.y - items[0].position.y,
+            scaled.height() + scaled.line_gap()
+        );
+    }
+
+    #[test]
+    fn layout_paragraph_wraps_long_text() {
+        let glyphs = layout(&"a".repeat(100), 300.0);
+        let first_y = glyphs[0].position.y;
+        assert!(glyphs.iter().any(|g| g.position.y > first_y));
+        assert!(glyphs.iter().all(|g| g.position.x <= 80.0 + 300.0));
+    }
+
+    #[test]
+    fn input_from_url_decodes_query() {
+        let url = Url::parse(
+            "https://ogpgen.example.com/?title=%E3%81%82+b&text=a%2Bb&flag&empty=&%zz=1",
+        )
+        .unwrap();
+        let input = input_from_url(&url).unwrap();
+        assert_eq!(query(&input, "title").as_deref(), Some("あ b"));
+        assert_eq!(query(&input, "text").as_deref(), Some("a+b"));
+        assert_eq!(query(&input, "flag").as_deref(), Some(""));
+        assert_eq!(query(&input, "empty").as_deref(), Some(""));
+        assert_eq!(query(&input, "%zz").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn input_from_url_rejects_invalid_utf8() {
+        let url = Url::parse("https://ogpgen.example.com/?title=%E3%81&text=b").unwrap();
+        assert_eq!(
+            input_from_url(&url).err(),
+            Some("title parameter is not valid UTF-8".to_string())
+        );
+        let url = Url::parse("https://ogpgen.example.com/?%FF=a").unwrap();
+        assert!(input_from_url(&url).is_err());
+    }
+
+    #[test]
+    fn input_from_json_flattens_values() {
+        let input = input_from_json(
+            r#"{"title": "t", "width": 800, "noindex": true, "items": ["a", "b"], "bg": null}"#,
+        )
+        .unwrap();
+        assert_eq!(query(&input, "title").as_deref(), Some("t"));
+        assert_eq!(query(&input, "width").as_deref(), Some("800"));
+        assert!(query_flag(&input, "noindex"));
+        assert_eq!(input.iter().filter(|(k, _)| k == "items").count(), 2);
+        assert!(query(&input, "bg").is_none());
+    }
+
+    #[test]
+    fn batch_entries_reads_ids() {
+        let entries =
+            batch_entries(r#"[{"id": "a", "title": "t"}, {"id": "b-2", "title": "u"}]"#).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "a");
+        assert_eq!(query(&entries[0].1, "title").as_deref(), Some("t"));
+        assert_eq!(query(&entries[0].1, "id"), None);
+        assert_eq!(entries[1].0, "b-2");
+
+        for body in [
+            r#"{"id": "a"}"#,
+            "[]",
+            r#"[{"title": "t"}]"#,
+            r#"[{"id": "../a", "title": "t"}]"#,
+            r#"[{"id": "a"}, {"id": "a"}]"#,
+            r#"[{"id": "a", "format": "jpeg"}]"#,
+            r#"["a"]"#,
+        ] {
+            assert!(batch_entries(body).is_err(), "{body}");
+        }
+    }
+
+    #[test]
+    fn parse_u8_in_range_reports_typed_errors() {
+        assert_eq!(parse_u8_in_range("80", 1, 100), Ok(80));
+        assert_eq!(parse_u8_in_range(" 1 ", 1, 100), Ok(1));
+        assert_eq!(parse_u8_in_range("abc", 1, 100), Err(NumberError::Invalid));
+        assert_eq!(parse_u8_in_range("1.5", 1, 100), Err(NumberError::Invalid));
+        for value in ["0", "101", "-1", "300", "99999999999"] {
+            assert_eq!(
+                parse_u8_in_range(value, 1, 100),
+                Err(NumberError::OutOfRange { min: 1, max: 100 })
+            );
+        }
+        assert_eq!(
+            parse_params(&test_input(&[("quality", "300")]), Variant::default()).err(),
+            Some("quality must be between 1 and 100".to_string())
+        );
+        assert_eq!(
+            parse_params(&test_input(&[("quality", "high")]), Variant::default()).err(),
+            Some("quality parameter is invalid".to_string())
+        );
+        assert_eq!(
+            parse_params(
+                &test_input(&[("format", "webp"), ("quality", "80")]),
+                Variant::default()
+            )
+            .err(),
+            Some("quality cannot be combined with format=webp".to_string())
+        );
+    }
+
+    #[test]
+    fn input_from_json_rejects_invalid_bodies() {
+        assert!(input_from_json("not json").is_err());
+        assert!(input_from_json("[1, 2]").is_err());
+        assert!(input_from_json(r#"{"title": {"nested": 1}}"#).is_err());
+    }
+
+    #[test]
+    fn parse_params_requires_fields() {
+        let input = test_input(&[]);
+        for key in ["title", "text"] {
+            let input: Vec<_> = input.iter().filter(|(k, _)| k != key).cloned().collect();
+            assert_eq!(
+                parse_params(&input, Variant::default()).err(),
+                Some(format!("{key} parameter is required"))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_params_rejects_blank_fields() {
+        for key in ["title", "text"] {
+            let input: Vec<_> = test_input(&[])
+                .into_iter()
+                .map(|(k, v)| {
+                    if k == key {
+                        (k, " \u{3000}\t".to_string())
+                    } else {
+                        (k, v)
+                    }
+                })
+                .collect();
+            assert_eq!(
+                parse_params(&input, Variant::default()).err(),
+                Some(format!("{key} must contain non-whitespace characters"))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_params_treats_blank_author_as_absent() {
+        let input: Vec<_> = test_input(&[("authorprefix", "by ")])
+            .into_iter()
+            .filter(|(k, _)| k != "author")
+            .collect();
+        assert_eq!(
+            parse_params(&input, Variant::default()).unwrap().author,
+            None
+        );
+
+        let mut blank = input.clone();
+        blank.push(("author".to_string(), "  ".to_string()));
+        assert_eq!(
+            parse_params(&blank, Variant::default()).unwrap().author,
+            None
+        );
+
+        let mut named = input;
+        named.push(("author".to_string(), "ucpr".to_string()));
+        assert_eq!(
+            parse_params(&named, Variant::default())
+                .unwrap()
+                .author
+                .as_deref(),
+            Some("by ucpr")
+        );
+    }
+
+    #[test]
+    fn parse_params_strips_unsafe_characters() {
+        let input: Vec<(String, String)> = [
+            ("title", "a\u{202E}b\u{2066}c\u{FEFF}"),
+            ("text", "\u{E0041}text\u{FFFF}"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.title, "abc");
+        assert_eq!(params.text, "text");
+        assert_eq!(sanitize_text("日本語 é"), "日本語 é");
+
+        let input: Vec<(String, String)> = [("title", "\u{200B}\u{200D}"), ("text", "text")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert!(parse_params(&input, Variant::default()).is_err());
+    }
+
+    #[test]
+    fn check_field_fonts_requires_allowed_keys() {
+        let params = parse_params(
+            &test_input(&[("titlefont", "Serif.ttf")]),
+            Variant::default(),
+        )
+        .unwrap();
+        assert_eq!(params.title_font.as_deref(), Some("Serif.ttf"));
+        assert!(check_field_fonts(&params, &["Serif.ttf".to_string()]).is_ok());
+        assert_eq!(
+            check_field_fonts(&params, &[]),
+            Err("titlefont is not allowed".to_string())
+        );
+        assert!(check_field_fonts(&test_params(), &[]).is_ok());
+    }
+
+    #[test]
+    fn parse_params_rejects_invalid_values() {
+        for extra in [
+            ("width", "abc"),
+            ("width", "10000"),
+            ("quality", "0"),
+            ("format", "bmp"),
+            ("color", "zzzzzz"),
+            ("position", "left"),
+            ("flip", "v"),
+            ("template", "../secret"),
+            ("template", "a/b.png"),
+            ("blur", "-1"),
+            ("dpi", "10"),
+            ("titlemaxlines", "0"),
+            ("titleminsize", "100"),
+            ("marginleft", "-1"),
+            ("marginleft", "NaN"),
+            ("marginright", "1200"),
+            ("notdef", "hide"),
+            ("bgimage", "http://example.com/a.png"),
+            ("bgimage", "not a url"),
+            ("alpha", "no"),
+            ("rule", "1"),
+            ("rule", "0,cccccc"),
+            ("rule", "1,ccc"),
+            ("radius", "-1"),
+            ("stroke", "0"),
+            ("stroke", "2,red"),
+            ("fill", "none"),
+            ("fill", "transparent"),
+            ("aa", "off"),
+            ("fit", "height"),
+            ("normalize", "nfc"),
+            ("accent", "red"),
+            ("accentcount", "4"),
+            ("accentposition", "bottom"),
+            ("textgradient", "ff0000"),
+            ("textgradient", "ff0000,blue"),
+            ("offsetx", "1001"),
+            ("offsety", "up"),
+            ("debug", "1"),
+            ("watermark", "this watermark is much too long to draw"),
+            ("subsampling", "444"),
+            ("anchor", "middle"),
+            ("align", "center"),
+            ("padding", "1emx"),
+            ("tracking", "2em"),
+            ("linespacing", "-1"),
+            ("panel", "000000"),
+            ("panel", "00000g80"),
+            ("titlebg", "ff0000"),
+            ("textbg", "blue"),
+            ("maxlines", "0"),
+            ("overflow", "shrink"),
+            ("authorpill", "zzzzzz"),
+            ("shadow", "2,2,4"),
+            ("categorycolor", "000000"),
+            ("shadow", "2,2,30,000000"),
+        ] {
+            assert!(parse_params(&test_input(&[extra]), Variant::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_params_rejects_expensive_renders() {
+        let params = |extra: &[(&str, &str)]| parse_params(&test_input(extra), Variant::default());
+        assert!(params(&[("width", "2400"), ("height", "2400"), ("format", "webp")]).is_ok());
+        assert!(params(&[("print", "1"), ("template", "a.png"), ("blur", "5")]).is_ok());
+        assert!(params(&[("print", "1"), ("template", "a.png"), ("blur", "20")]).is_err());
+    }
+
+    #[test]
+    fn parse_params_resolves_ratio() {
+        let size = |ratio| {
+            let params =
+                parse_params(&test_input(&[("ratio", ratio)]), Variant::default()).unwrap();
+            (params.width, params.height)
+        };
+        assert_eq!(size("1.91"), (1200, 630));
+        assert_eq!(size("1"), (1200, 1200));
+        assert_eq!(size("2"), (1200, 600));
+        assert!(parse_params(
+            &test_input(&[("ratio", "1"), ("width", "800")]),
+            Variant::default()
+        )
+        .is_err());
+        assert!(parse_params(&test_input(&[("ratio", "4:3")]), Variant::default()).is_err());
+    }
+
+    #[test]
+    fn parse_params_prefers_explicit_values_over_variant() {
+        let variant = builtin_variant("twitter").unwrap();
+        let params = parse_params(&test_input(&[("height", "700")]), variant).unwrap();
+        assert_eq!((params.width, params.height), (1200, 700));
+    }
+
+    #[test]
+    fn is_crawler_matches_social_user_agents() {
+        assert!(is_crawler(
+            "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)"
+        ));
+        assert!(is_crawler("Twitterbot/1.0"));
+        assert!(!is_crawler(
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 Chrome/120.0 Safari/537.36"
+        ));
+    }
+
+    #[test]
+    fn font_signature_identifies_formats() {
+        assert_eq!(font_signature(TEST_FONT), "TrueType signature");
+        assert_eq!(
+            font_signature(b"wOF2...."),
+            "WOFF signature, which is not supported"
+        );
+        assert_eq!(font_signature(b"<html>"), "unknown signature");
+        assert_eq!(font_signature(b""), "too short to have a signature");
+    }
+
+    #[test]
+    fn font_content_type_follows_signature() {
+        assert_eq!(font_content_type(TEST_FONT), "font/ttf");
+        assert_eq!(font_content_type(b"OTTO...."), "font/otf");
+        assert_eq!(font_content_type(b"wOF2...."), "font/woff2");
+        assert_eq!(font_content_type(b"<html>"), "application/octet-stream");
+        assert_eq!(font_content_type(b""), "application/octet-stream");
+    }
+
+    #[test]
+    fn single_flight_renders_once_per_key() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::task::{Context, Poll};
+
+        let renders = Rc::new(Cell::new(0));
+        let render = |renders: Rc<Cell<usize>>| async move {
+            renders.set(renders.get() + 1);
+            // 1 回だけ Pending を返して、ほかのリクエストが待つ状況を作る
+            let mut yielded = false;
+            futures_util::future::poll_fn(|cx| {
+                if yielded {
+                    Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .await;
+            Ok(Rendered {
+                buffer: vec![1, 2, 3],
+                format: OutputFormat::Png,
+                degraded: false,
+            })
+        };
+        let mut both = std::pin::pin!(futures_util::future::join(
+            single_flight("key", render(renders.clone())),
+            single_flight("key", render(renders.clone())),
+        ));
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+        let ((a, a_leader), (b, b_leader)) = loop {
+            if let Poll::Ready(results) = both.as_mut().poll(&mut cx) {
+                break results;
+            }
+        };
+        assert_eq!(renders.get(), 1);
+        assert_eq!(a.unwrap().buffer, b.unwrap().buffer);
+        // キャッシュに保存するのは最初のリクエストだけ
+        assert!(a_leader);
+        assert!(!b_leader);
+        assert!(IN_FLIGHT.with(|in_flight| in_flight.borrow().is_empty()));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_bytes() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+    }
+
+    fn test_cache_key(query: &str) -> String {
+        let url = Url::parse(&format!("https://ogpgen.example.com/?{query}")).unwrap();
+        cache_key(&url, &input_from_url(&url).unwrap(), &["v".to_string()])
+    }
+
+    #[test]
+    fn params_etag_follows_cache_key() {
+        let etag = params_etag(&test_cache_key("title=a&text=b"));
+        assert_eq!(etag, params_etag(&test_cache_key("text=b&title=a")));
+        assert_ne!(etag, params_etag(&test_cache_key("title=a&text=c")));
+        assert_eq!(etag.len(), 18);
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"x\", W/{etag}"), &etag));
+        assert!(!etag_matches("*", &etag));
+        assert!(!etag_matches("\"x\"", &etag));
+    }
+
+    #[test]
+    fn meta_tags_reference_image_url() {
+        let url = Url::parse("https://example.com/?title=a&format=meta").unwrap();
+        let input: Vec<_> = test_input(&[("format", "meta")])
+            .into_iter()
+            .filter(|(k, _)| k != "format")
+            .collect();
+        let image_url = image_url(&url, &input);
+        assert_eq!(
+            image_url,
+            "https://example.com/?title=title&text=text&author=author"
+        );
+        let mut params = test_params();
+        params.title = "\"quoted\" & <b>".to_string();
+        let tags = meta_tags(&params, &image_url);
+        assert!(tags.contains(
+            r#"<meta property="og:title" content="&quot;quoted&quot; &amp; &lt;b&gt;">"#
+        ));
+        assert!(tags.contains(&format!(
+            r#"<meta name="twitter:image" content="{}">"#,
+            escape_xml(&image_url)
+        )));
+        assert!(tags.contains(r#"<meta property="og:image:width" content="1200">"#));
+        assert!(tags.contains(r#"<meta property="og:description" content="text">"#));
+        assert!(tags.contains(r#"<meta name="author" content="author">"#));
+    }
+
+    #[test]
+    fn cache_key_ignores_parameter_order() {
+        assert_eq!(
+            test_cache_key("title=a&text=b&author=c&format=png"),
+            test_cache_key("format=png&author=c&text=b&title=a")
+        );
+    }
+
+    #[test]
+    fn cache_key_differs_by_parameters() {
+        let keys = [
+            test_cache_key("title=a&text=b&author=c"),
+            test_cache_key("title=x&text=b&author=c"),
+            test_cache_key("title=a&text=x&author=c"),
+            test_cache_key("title=a&text=b&author=x"),
+            test_cache_key("title=a&text=b&author=c&format=jpeg"),
+            test_cache_key("title=a&text=b&author=c&format=webp"),
+            test_cache_key("title=ab&text=&author=c"),
+        ];
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn cache_key_ignores_cache_busters() {
+        assert_eq!(
+            test_cache_key("title=a&text=b&v=1"),
+            test_cache_key("title=a&v=2&text=b")
+        );
+        assert_eq!(
+            test_cache_key("title=a&text=b&v=1"),
+            test_cache_key("title=a&text=b")
+        );
+
+        let url = Url::parse("https://ogpgen.example.com/?title=a&utm_source=x").unwrap();
+        let ignored = ["utm_source".to_string()];
+        assert_eq!(
+            cache_key(&url, &input_from_url(&url).unwrap(), &ignored),
+            test_cache_key("title=a")
+        );
+    }
+
+    #[test]
+    fn cache_key_keeps_order_of_repeated_parameters() {
+        assert_ne!(
+            test_cache_key("item=a&item=b"),
+            test_cache_key("item=b&item=a")
+        );
+    }
+
+    #[test]
+    fn cache_key_matches_post_body() {
+        let url = Url::parse("https://ogpgen.example.com/").unwrap();
+        let input = input_from_json(r#"{"title": "a", "text": "b", "author": "c"}"#).unwrap();
+        assert_eq!(
+            cache_key(&url, &input, &[]),
+            test_cache_key("text=b&author=c&title=a")
+        );
+    }
+
+    #[test]
+    fn layout_runs_aligns_baselines() {
+        let font = test_font();
+        let mut glyphs = Vec::new();
+        let caret = layout_runs(
+            &[
+                (font.as_scaled(PxScale::from(96.0)), "42"),
+                (font.as_scaled(PxScale::from(36.0)), " users"),
+            ],
+            point(80.0, 80.0),
+            LayoutOptions::default().with_max_width(1000.0),
+            &mut glyphs,
+        );
+        let baseline = 80.0 + font.as_scaled(PxScale::from(96.0)).ascent();
+        assert_eq!(glyphs.len(), 8);
+        assert!(glyphs.iter().all(|g| g.position.y == baseline));
+        assert_eq!(glyphs[0].scale, PxScale::from(96.0));
+        assert_eq!(glyphs[7].scale, PxScale::from(36.0));
+        assert!(glyphs[2].position.x > glyphs[1].position.x);
+        assert_eq!(caret.y, baseline);
+    }
+
+    #[test]
+    fn parse_params_requires_stat_for_label() {
+        assert_eq!(
+            parse_params(&test_input(&[("statlabel", "users")]), Variant::default()).err(),
+            Some("statlabel parameter requires stat".to_string())
+        );
+        let params = parse_params(
+            &test_input(&[("stat", "42"), ("statlabel", "users")]),
+            Variant::default(),
+        )
+        .unwrap();
+        assert_eq!(params.stat.as_deref(), Some("42"));
+        generate_card(&test_font(), &params, None);
+    }
+
+    #[test]
+    fn fill_template_replaces_placeholders() {
+        let input = test_input(&[("arg", "one"), ("arg", "two")]);
+        assert_eq!(
+            fill_template("{title} by {author}: {1}, {2} {{x}}", &input).as_deref(),
+            Ok("title by author: one, two {x}")
+        );
+        assert!(fill_template("{3}", &input).is_err());
+        assert!(fill_template("{missing}", &input).is_err());
+        assert!(fill_template("{title", &input).is_err());
+        assert!(fill_template("title}", &input).is_err());
+    }
+
+    #[test]
+    fn parse_params_fills_text_template() {
+        let input: Vec<_> = test_input(&[("texttemplate", "{1} posts by {author}"), ("arg", "42")])
+            .into_iter()
+            .filter(|(k, _)| k != "text")
+            .collect();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.text, "42 posts by author");
+        assert!(parse_params(
+            &test_input(&[("texttemplate", "{title}")]),
+            Variant::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn split_markdown_extracts_heading() {
+        assert_eq!(
+            split_markdown("# Title\n\nfirst\nline\n\nsecond\n"),
+            (Some("Title".to_string()), "first line\nsecond".to_string())
+        );
+        assert_eq!(
+            split_markdown("no heading"),
+            (None, "no heading".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_params_reads_markdown() {
+        let input = vec![("md".to_string(), "# Hello\nworld".to_string())];
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.title, "Hello");
+        assert_eq!(params.text, "world");
+
+        let input: Vec<_> = test_input(&[("md", "body only")])
+            .into_iter()
+            .filter(|(k, _)| k != "text")
+            .collect();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.title, "title");
+        assert_eq!(params.text, "body only");
+
+        assert!(parse_params(&test_input(&[("md", "# a\nb")]), Variant::default()).is_err());
+    }
+
+    #[test]
+    fn layout_list_hangs_wrapped_lines() {
+        let font = test_font();
+        let scaled = font.as_scaled(PxScale::from(60.0));
+        let items = vec!["a".repeat(40), "b".to_string()];
+        let mut glyphs = Vec::new();
+        layout_list(
+            scaled,
+            point(80.0, 80.0),
+            LayoutOptions::default().with_max_width(600.0),
+            &items,
+            ListStyle::Bullet,
+            &mut glyphs,
+        );
+        let bullet = scaled.glyph_id('•');
+        let bullets: Vec<&Glyph> = glyphs.iter().filter(|g| g.id == bullet).collect();
+        assert_eq!(bullets.len(), 2);
+        assert!(bullets.iter().all(|g| g.position.x == 80.0));
+
+        let indent = glyphs[1].position.x;
+        assert!(indent > 80.0);
+        let line_starts: Vec<&Glyph> = glyphs
+            .windows(2)
+            .filter(|w| w[1].position.y > w[0].position.y && w[1].id != bullet)
+            .map(|w| &w[1])
+            .collect();
+        assert!(!line_starts.is_empty());
+        assert!(line_starts.iter().all(|g| g.position.x == indent));
+    }
+
+    #[test]
+    fn alt_text_describes_card() {
+        assert_eq!(test_params().alt_text(), "title — text by author");
+        let mut params = test_params();
+        params.author = None;
+        params.items = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(params.alt_text(), "title — a, b");
+    }
+
+    #[test]
+    fn parse_params_reads_items() {
+        let input: Vec<_> = test_input(&[("item", "one"), ("item", "two"), ("list", "number")])
+            .into_iter()
+            .filter(|(k, _)| k != "text")
+            .collect();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.items, vec!["one", "two"]);
+        assert_eq!(params.list_style, ListStyle::Number);
+        assert!(parse_params(&test_input(&[("item", "one")]), Variant::default()).is_err());
+    }
+
+    #[test]
+    fn charset_covers_subset_texts() {
+        let ranges = parse_char_ranges(&["0020-007e".to_string(), "00e9".to_string()]).unwrap();
+        assert_eq!(ranges, vec![0x20..=0x7e, 0xe9..=0xe9]);
+        assert!(parse_char_ranges(&["007e-0020".to_string()]).is_none());
+        assert!(parse_char_ranges(&["zz".to_string()]).is_none());
+
+        let mut params = test_params();
+        params.text = "café\nmenu".to_string();
+        assert!(charset_covers(&ranges, params.subset_texts()));
+        params.badge = Some("日本".to_string());
+        assert!(!charset_covers(&ranges, params.subset_texts()));
+        params.badge = None;
+        params.items = vec!["a".to_string()];
+        assert!(!charset_covers(&ranges, params.subset_texts()));
+        params.list_style = ListStyle::Number;
+        assert!(charset_covers(&ranges, params.subset_texts()));
+    }
+
+    #[test]
+    fn layout_text_applies_overflow() {
+        let font = test_font();
+        let scale = PxScale::from(70.0);
+        let position = point(80.0, 80.0);
+        let text = "long text ".repeat(9);
+        let layout = |overflow| {
+            LayoutOptions::default()
+                .with_max_width(1000.0)
+                .with_max_lines(Some(2), overflow)
+        };
+        let mut unclamped = Vec::new();
+        layout_text(
+            &font,
+            scale,
+            position,
+            LayoutOptions::default().with_max_width(1000.0),
+            &text,
+            &mut unclamped,
+        );
+        assert!(count_lines(&unclamped) > 2);
+
+        let mut clipped = Vec::new();
+        assert_eq!(
+            layout_text(
+                &font,
+                scale,
+                position,
+                layout(Overflow::Clip),
+                &text,
+                &mut clipped
+            ),
+            scale
+        );
+        assert_eq!(count_lines(&clipped), 2);
+        assert_eq!(clipped[..], unclamped[..clipped.len()]);
+
+        let mut ellipsized = Vec::new();
+        layout_text(
+            &font,
+            scale,
+            position,
+            layout(Overflow::Ellipsis),
+            &text,
+            &mut ellipsized,
+        );
+        assert!(count_lines(&ellipsized) <= 2);
+        assert_eq!(ellipsized.last().unwrap().id, font.glyph_id('…'));
+
+        let mut shrunk = Vec::new();
+        let shrunk_scale = layout_text(
+            &font,
+            scale,
+            position,
+            layout(Overflow::Shrink),
+            &text,
+            &mut shrunk,
+        );
+        assert!(shrunk_scale.y < scale.y && shrunk_scale.y >= MIN_TEXT_FONT_SIZE);
+        assert!(count_lines(&shrunk) <= 2);
+        assert_eq!(shrunk.len(), unclamped.len());
+    }
+
+    #[test]
+    fn fit_title_scale_shrinks_long_titles() {
+        let font = test_font();
+        let mut params = test_params();
+        let position = point(80.0, 80.0);
+        assert_eq!(
+            fit_title_scale(
+                &font,
+                &params,
+                position,
+                LayoutOptions::default().with_max_width(1000.0)
+            ),
+            PxScale::from(TITLE_FONT_SIZE)
+        );
+
+        params.title = "long title ".repeat(10);
+        let layout = LayoutOptions::default().with_max_width(1000.0);
+        let scale = fit_title_scale(&font, &params, position, layout);
+        assert!(scale.y < TITLE_FONT_SIZE);
+        assert!(scale.y >= params.title_min_size);
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            font.as_scaled(scale),
+            position,
+            layout,
+            &params.title,
+            &mut glyphs,
+        );
+        assert!(count_lines(&glyphs) <= params.title_max_lines);
+
+        params.title = "long title ".repeat(60);
+        assert_eq!(
+            fit_title_scale(&font, &params, position, layout),
+            PxScale::from(params.title_min_size)
+        );
+    }
+
+    #[test]
+    fn parse_params_normalizes_width_variants() {
+        let input: Vec<(String, String)> = [
+            ("title", "ＡＢＣ１２３"),
+            ("text", "ｶﾀｶﾅ"),
+            ("textgradient", "ff0000,0000ff"),
+            ("normalize", "nfkc"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        // 指定しなければ入力のまま描く
+        let raw = parse_params(&input[..3], Variant::default()).unwrap();
+        assert_eq!(raw.title, "ＡＢＣ１２３");
+        assert_eq!(raw.text, "ｶﾀｶﾅ");
+        let normalized = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(normalized.title, "ABC123");
+        assert_eq!(normalized.text, "カタカナ");
+        assert_eq!(normalized.text_gradient, raw.text_gradient);
+    }
+
+    #[test]
+    fn fit_title_scale_fills_max_width() {
+        let font = test_font();
+        let mut params =
+            parse_params(&test_input(&[("fit", "width")]), Variant::default()).unwrap();
+        let position = point(80.0, 80.0);
+        let layout = LayoutOptions::default().with_max_width(1000.0);
+        let width = |params: &Params, scale: PxScale| {
+            measure_text(&font, scale, &params.title, position, layout)
+                .unwrap()
+                .width()
+        };
+
+        params.title = "Breaking News".to_string();
+        let scale = fit_title_scale(&font, &params, position, layout);
+        assert!(scale.y > TITLE_FONT_SIZE);
+        assert!((width(&params, scale) - 1000.0).abs() < 1.0);
+
+        params.title = "a somewhat longer title that has to shrink".to_string();
+        let scale = fit_title_scale(&font, &params, position, layout);
+        assert!(scale.y < TITLE_FONT_SIZE);
+        assert!((width(&params, scale) - 1000.0).abs() < 1.0);
+        // 描画するときも折り返さずに 1 行に並ぶ
+        let mut glyphs = Vec::new();
+        layout_paragraph(
+            font.as_scaled(scale),
+            position,
+            title_layout(&params, layout),
+            &params.title,
+            &mut glyphs,
+        );
+        assert_eq!(count_lines(&glyphs), 1);
+
+        // 1 文字でも上限より大きくはしない
+        params.title = "i".to_string();
+        assert_eq!(
+            fit_title_scale(&font, &params, position, layout),
+            PxScale::from(MAX_FIT_TITLE_SIZE)
+        );
+    }
+
+    #[test]
+    fn generate_card_has_card_size() {
+        let imgbuf = generate_card(&test_font(), &test_params(), None);
+        assert_eq!(imgbuf.dimensions(), (IMAGE_WIDTH, IMAGE_HEIGHT));
+    }
+
+    #[test]
+    fn generate_card_uses_requested_size() {
+        let mut params = test_params();
+        params.width = 1200;
+        params.height = 1200;
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert_eq!(imgbuf.dimensions(), (1200, 1200));
+    }
+
+    #[test]
+    fn builtin_variants_are_resolved() {
+        let variant = builtin_variant("twitter").unwrap();
+        assert_eq!(variant.width, Some(1200));
+        assert_eq!(variant.height, Some(600));
+        assert!(builtin_variant("unknown").is_none());
+    }
+
+    #[test]
+    fn variants_are_parsed_from_json() {
+        let variants: HashMap<String, Variant> =
+            serde_json::from_str(r#"{"og": {"width": 800, "format": "jpg", "quality": 90}}"#)
+                .unwrap();
+        let variant = variants["og"];
+        assert_eq!(variant.width, Some(800));
+        assert_eq!(variant.height, None);
+        assert_eq!(variant.format, Some(OutputFormat::Jpeg));
+        assert_eq!(variant.quality, Some(90));
+    }
+
+    #[test]
+    fn themes_are_parsed_from_json() {
+        let themes: HashMap<String, Theme> = serde_json::from_str(
+            r##"{"acme": {"font": "Acme.ttf", "bg": "#112233", "titlemaxlines": 3}}"##,
+        )
+        .unwrap();
+        let theme = find_theme(&themes, "acme").unwrap();
+        assert_eq!(theme.font.as_deref(), Some("Acme.ttf"));
+        assert!(!theme.params.contains_key("font"));
+        assert_eq!(
+            find_theme(&themes, "unknown").err(),
+            Some("theme unknown is not defined (available: acme)".to_string())
+        );
+    }
+
+    #[test]
+    fn apply_theme_keeps_request_values() {
+        let theme: Theme =
+            serde_json::from_str(r#"{"bg": "112233", "color": "ffffff", "item": ["a"]}"#).unwrap();
+        let mut input = test_input(&[("bg", "000000"), ("item", "b"), ("item", "c")]);
+        input.retain(|(k, _)| k != "text");
+        apply_theme(&mut input, &theme, ColorMode::Light).unwrap();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.items, ["b", "c"]);
+        assert_eq!(params.background, (0, 0, 0));
+        assert_eq!(params.title_color, (255, 255, 255));
+    }
+
+    #[test]
+    fn detect_script_prefers_non_latin_scripts() {
+        assert_eq!(detect_script(["Hello, world"]), Some(Script::Latin));
+        assert_eq!(
+            detect_script(["Rust で作る", "WebAssembly"]),
+            Some(Script::Cjk)
+        );
+        assert_eq!(detect_script(["Привет", "world"]), Some(Script::Cyrillic));
+        assert_eq!(detect_script(["こんにちは", "Привет"]), None);
+        assert_eq!(detect_script(["2024 / 12"]), None);
+    }
+
+    #[test]
+    fn apply_theme_prefers_dark_values_in_dark_mode() {
+        let theme: Theme = serde_json::from_str(
+            r#"{"bg": "ffffff", "color": "000000", "dark": {"bg": "000000"}}"#,
+        )
+        .unwrap();
+        let mut input = test_input(&[]);
+        apply_theme(&mut input, &theme, ColorMode::Dark).unwrap();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.background, (0, 0, 0));
+        assert_eq!(params.title_color, (0, 0, 0));
+
+        let mut input = test_input(&[]);
+        apply_theme(&mut input, &Theme::default(), ColorMode::Dark).unwrap();
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(params.background, (0x1e, 0x1e, 0x1e));
+    }
+
+    #[test]
+    fn parse_color_mode_reads_colorscheme_for_auto() {
+        let mode = |extra: &[(&str, &str)]| parse_color_mode(&test_input(extra));
+        assert_eq!(mode(&[]), Ok(ColorMode::Light));
+        assert_eq!(mode(&[("mode", "dark")]), Ok(ColorMode::Dark));
+        assert_eq!(
+            mode(&[("mode", "auto"), ("colorscheme", "dark")]),
+            Ok(ColorMode::Dark)
+        );
+        assert_eq!(mode(&[("mode", "auto")]), Ok(ColorMode::Light));
+        // colorscheme は mode=auto のときだけ使う
+        assert_eq!(
+            mode(&[("mode", "light"), ("colorscheme", "dark")]),
+            Ok(ColorMode::Light)
+        );
+        assert!(mode(&[("mode", "auto"), ("colorscheme", "dim")]).is_err());
+        assert!(mode(&[("mode", "sepia")]).is_err());
+    }
+
+    #[test]
+    fn generate_card_draws_text() {
+        let imgbuf = generate_card(&test_font(), &test_params(), None);
+        assert!(imgbuf.pixels().any(|px| *px != Rgba([255, 255, 255, 255])));
+    }
+
+    #[test]
+    fn embedded_font_covers_ascii() {
+        let font = FontRef::try_from_slice(EMBEDDED_FONT).unwrap();
+        for c in (' '..='~').chain(['…', '•']) {
+            assert_ne!(font.glyph_id(c).0, 0, "{c:?}");
+        }
+        assert_eq!(font.glyph_id('あ').0, 0);
+        let glyph = font.glyph_id('A').with_scale(60.0);
+        assert!(font.outline_glyph(glyph).is_some());
+        // 元のフォントと同じ幅で並ぶ
+        let original = test_font();
+        assert_eq!(
+            font.h_advance_unscaled(font.glyph_id('A')),
+            original.h_advance_unscaled(original.glyph_id('A'))
+        );
+        let (_, degraded) = parse_fonts(Some(b"not a font".to_vec()), Vec::new()).unwrap();
+        assert!(degraded);
+        let (_, degraded) = parse_fonts(None, Vec::new()).unwrap();
+        assert!(degraded);
+        let (_, degraded) = parse_fonts(Some(TEST_FONT.to_vec()), Vec::new()).unwrap();
+        assert!(!degraded);
+    }
+
+    #[test]
+    fn measure_paragraph_counts_wrapped_lines() {
+        let params = parse_measure_params(&[
+            ("text".to_string(), "word ".repeat(10)),
+            ("size".to_string(), "60".to_string()),
+            ("maxwidth".to_string(), "400".to_string()),
+        ])
+        .unwrap();
+        let measurement = measure_paragraph(&test_font(), &params);
+        let one_line = measure_paragraph(
+            &test_font(),
+            &MeasureParams {
+                max_width: 2400.0,
+                ..params
+            },
+        );
+        assert_eq!(one_line.lines, 1);
+        assert!(measurement.lines > 1);
+        assert!(measurement.width < one_line.width);
+        assert!(measurement.height > one_line.height);
+        assert!(parse_measure_params(&[("text".to_string(), "a".to_string())]).is_ok());
+        assert!(parse_measure_params(&[
+            ("text".to_string(), "a".to_string()),
+            ("size".to_string(), "1000".to_string()),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn measure_text_covers_every_line() {
+        let font = test_font();
+        let layout = LayoutOptions::default().with_max_width(1000.0);
+        let one_line =
+            measure_text(&font, PxScale::from(60.0), "a", point(80.0, 80.0), layout).unwrap();
+        let two_lines = measure_text(
+            &font,
+            PxScale::from(60.0),
+            "a\na",
+            point(80.0, 80.0),
+            layout,
+        )
+        .unwrap();
+        assert_eq!(one_line.min, point(80.0, 80.0));
+        assert!(two_lines.height() > one_line.height());
+        assert!(measure_text(&font, PxScale::from(60.0), "", point(80.0, 80.0), layout).is_none());
+    }
+
+    #[test]
+    fn generate_card_moves_text_group_to_bottom() {
+        let mut params = test_params();
+        params.author = None;
+        let top = generate_card(&test_font(), &params, None);
+        params.position = VerticalPosition::Bottom;
+        let bottom = generate_card(&test_font(), &params, None);
+        let first_ink_row = |imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            imgbuf
+                .enumerate_pixels()
+                .find(|(_, _, px)| **px != Rgba([255, 255, 255, 255]))
+                .map(|(_, y, _)| y)
+                .unwrap()
+        };
+        assert!(first_ink_row(&bottom) > first_ink_row(&top));
+    }
+
+    #[test]
+    fn fill_rounded_rect_leaves_corners_uncovered() {
+        let mut imgbuf = ImageBuffer::from_pixel(40, 20, Rgba([255, 255, 255, 255]));
+        let rect = Rect {
+            min: point(0.0, 0.0),
+            max: point(40.0, 20.0),
+        };
+        fill_rounded_rect(&mut imgbuf, rect, 10.0, (0, 0, 0), 1.0);
+        assert_eq!(*imgbuf.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(*imgbuf.get_pixel(20, 10), Rgba([0, 0, 0, 255]));
+        assert_eq!(*imgbuf.get_pixel(10, 0), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn generate_card_skips_missing_glyphs_by_default() {
+        let mut params = test_params();
+        params.title = "t".to_string();
+        let plain = generate_card(&test_font(), &params, None);
+        // DejaVu Sans には私用領域の文字が含まれない
+        params.title = "t\u{E000}".to_string();
+        let skipped = generate_card(&test_font(), &params, None);
+        assert!(plain == skipped);
+        params.notdef = Notdef::Box;
+        let boxed = generate_card(&test_font(), &params, None);
+        assert!(plain != boxed);
+    }
+
+    #[test]
+    fn generate_card_rounds_corners() {
+        let mut params = test_params();
+        params.radius = 10000.0;
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert_eq!(imgbuf.get_pixel(0, 0).0[3], 0);
+        assert_eq!(
+            imgbuf.get_pixel(params.width - 1, params.height - 1).0[3],
+            0
+        );
+        assert_eq!(imgbuf.get_pixel(params.width / 2, 0).0[3], 255);
+        params.radius = 24.0;
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert_eq!(imgbuf.get_pixel(0, 0).0[3], 0);
+        assert_eq!(imgbuf.get_pixel(24, 24).0[3], 255);
+    }
+
+    #[test]
+    fn generate_card_draws_rule_between_title_and_text() {
+        let params =
+            parse_params(&test_input(&[("rule", "4,ff0000")]), Variant::default()).unwrap();
+        assert_eq!(
+            params.rule,
+            Some(Rule {
+                thickness: 4.0,
+                color: (255, 0, 0)
+            })
+        );
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let rows: Vec<u32> = (0..params.height)
+            .filter(|&y| *imgbuf.get_pixel(params.width / 2, y) == Rgba([255, 0, 0, 255]))
+            .collect();
+        assert_eq!(rows.len(), 4);
+        let font = test_font();
+        let title_bounds = measure_text(
+            &font,
+            PxScale::from(TITLE_FONT_SIZE),
+            "title",
+            point(params.margin_left, 80.0),
+            LayoutOptions::default(),
+        )
+        .unwrap();
+        assert!(rows[0] as f32 >= title_bounds.max.y);
+    }
+
+    #[test]
+    fn generate_card_draws_accent_bars_near_title() {
+        let input = test_input(&[("accent", "ff0000"), ("accentcount", "2")]);
+        let params = parse_params(&input, Variant::default()).unwrap();
+        assert_eq!(
+            params.accent,
+            Some(Accent {
+                color: (255, 0, 0),
+                count: 2,
+                position: AccentPosition::Top,
+            })
+        );
+        let red = Rgba([255, 0, 0, 255]);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let title_top = 80.0;
+        let y = (title_top - ACCENT_GAP - ACCENT_THICKNESS / 2.0) as u32;
+        let bars: Vec<u32> = (0..params.width)
+            .filter(|&x| *imgbuf.get_pixel(x, y) == red)
+            .collect();
+        assert_eq!(bars.len(), 2 * ACCENT_LENGTH as usize);
+        assert_eq!(bars[0], params.margin_left as u32);
+
+        let mut params = params;
+        params.accent = params.accent.map(|accent| Accent {
+            position: AccentPosition::Left,
+            ..accent
+        });
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let y = (title_top + ACCENT_LENGTH / 2.0) as u32;
+        let bars: Vec<u32> = (0..params.width)
+            .filter(|&x| *imgbuf.get_pixel(x, y) == red)
+            .collect();
+        assert_eq!(bars.len(), 2 * ACCENT_THICKNESS as usize);
+        assert!(bars.iter().all(|&x| (x as f32) < params.margin_left));
+    }
+
+    #[test]
+    fn generate_card_clips_long_author() {
+        let mut params = test_params();
+        params.author = Some("a".repeat(40));
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert_eq!(imgbuf.dimensions(), (params.width, params.height));
+    }
+
+    #[test]
+    fn bgimage_hosts_are_matched_exactly() {
+        let hosts = vec!["images.example.com".to_string()];
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(is_allowed_host(
+            &url("https://images.example.com/a.png"),
+            &hosts
+        ));
+        assert!(!is_allowed_host(
+            &url("https://images.example.com.evil.test/a.png"),
+            &hosts
+        ));
+        assert!(!is_allowed_host(&url("https://example.com/a.png"), &hosts));
+        assert!(!is_allowed_host(
+            &url("https://images.example.com/a.png"),
+            &[]
+        ));
+    }
+
+    #[test]
+    fn decode_remote_image_limits_dimensions() {
+        let encode = |width, height| {
+            let imgbuf = ImageBuffer::from_pixel(width, height, Rgba([0u8, 0, 0, 255]));
+            let mut buffer = std::io::Cursor::new(vec![]);
+            imgbuf
+                .write_to(&mut buffer, image::ImageFormat::Png)
+                .unwrap();
+            buffer.into_inner()
+        };
+        assert!(decode_remote_image(&encode(10, 10)).is_ok());
+        assert!(decode_remote_image(&encode(MAX_BGIMAGE_SIZE + 1, 1)).is_err());
+        assert!(decode_remote_image(b"not an image").is_err());
+    }
+
+    #[test]
+    fn highlight_mask_marks_matching_characters() {
+        assert_eq!(
+            highlight_mask("a Rust b\nRust", "Rust"),
+            [false, false, true, true, true, true, false, false, true, true, true, true]
+        );
+        assert!(highlight_mask("abc", "x").iter().all(|&m| !m));
+    }
+
+    #[test]
+    fn generate_card_draws_highlight_color() {
+        let params = parse_params(
+            &test_input(&[("highlight", "title"), ("highlightcolor", "ff0000")]),
+            Variant::default(),
+        )
+        .unwrap();
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert!(imgbuf.pixels().any(|px| *px == Rgba([255, 0, 0, 255])));
+        assert!(parse_params(
+            &test_input(&[("highlightcolor", "ff0000")]),
+            Variant::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn generate_card_aligns_site_name_to_right_margin() {
+        let mut params = parse_params(
+            &test_input(&[("sitename", "example.com"), ("color", "ff0000")]),
+            Variant::default(),
+        )
+        .unwrap();
+        params.title_color = (0, 0, 0);
+        params.text_color = (0, 0, 0);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let red_columns: Vec<u32> = imgbuf
+            .enumerate_pixels()
+            .filter(|(_, _, px)| px.0[0] > 200 && px.0[1] < 50)
+            .map(|(x, _, _)| x)
+            .collect();
+        let right_edge = params.width as f32 - params.margin_right;
+        // author は左端から、sitename は右端で終わる
+        assert!(red_columns
+            .iter()
+            .any(|&x| (x as f32) < params.margin_left + 60.0));
+        let max_x = *red_columns.iter().max().unwrap() as f32;
+        assert!(max_x <= right_edge && max_x > right_edge - 10.0);
+        assert!(parse_params(
+            &test_input(&[("sitename", "a"), ("stat", "1")]),
+            Variant::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn generate_card_places_author_in_footer() {
+        let params =
+            parse_params(&test_input(&[("footer", "120,1e3a8a")]), Variant::default()).unwrap();
+        assert_eq!(params.author_color, (255, 255, 255));
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let band_top = params.height - 120;
+        assert_eq!(
+            *imgbuf.get_pixel(5, band_top + 2),
+            Rgba([0x1e, 0x3a, 0x8a, 255])
+        );
+        assert_eq!(
+            *imgbuf.get_pixel(5, band_top - 2),
+            Rgba([255, 255, 255, 255])
+        );
+        // author の白い文字は帯の中にだけある
+        let white_rows: Vec<u32> = imgbuf
+            .enumerate_pixels()
+            .filter(|(_, _, px)| px.0[2] > 200 && px.0[0] > 200)
+            .map(|(_, y, _)| y)
+            .filter(|&y| y >= band_top)
+            .collect();
+        assert!(!white_rows.is_empty());
+
+        assert_eq!(contrast_color((0xff, 0xee, 0x88)), (0, 0, 0));
+        for extra in [
+            [("footer", "10,000000")],
+            [("footer", "100")],
+            [("footer", "100,xyz")],
+        ] {
+            assert!(parse_params(&test_input(&extra), Variant::default()).is_err());
+        }
+        assert!(parse_params(
+            &test_input(&[("footer", "100,000000"), ("stat", "1")]),
+            Variant::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_params_reads_columns() {
+        let input = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let params = parse_params(
+            &input(&[("title", "title"), ("left", "a"), ("right", "b")]),
+            Variant::default(),
+        )
+        .unwrap();
+        assert_eq!(params.columns, Some(("a".to_string(), "b".to_string())));
+        assert!(params.text.is_empty());
+        assert!(parse_params(&input(&[("left", "a")]), Variant::default()).is_err());
+        assert!(parse_params(
+            &input(&[("text", "t"), ("left", "a"), ("right", "b")]),
+            Variant::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn generate_card_draws_two_columns() {
+        let mut params = test_params();
+        params.text = String::new();
+        params.title = String::new();
+        params.author = None;
+        params.columns = Some(("left".to_string(), "right".to_string()));
+        params.background = (255, 255, 255);
+        params.text_color = (0, 0, 0);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let center = params.width / 2;
+        let has_ink = |xs: std::ops::Range<u32>| {
+            xs.into_iter()
+                .any(|x| (0..params.height).any(|y| imgbuf.get_pixel(x, y).0[0] < 128))
+        };
+        assert!(has_ink(0..center));
+        assert!(has_ink(center..params.width));
+    }
+
+    #[test]
+    fn generate_card_draws_author_pill() {
+        let params =
+            parse_params(&test_input(&[("authorpill", "000080")]), Variant::default()).unwrap();
+        assert_eq!(params.author_color, (255, 255, 255));
+        let imgbuf = generate_card(&test_font(), &params, None);
+        // author の左の余白が pill の色で塗られる
+        let x = params.width - 200 - 12;
+        let pill_rows = (0..params.height)
+            .filter(|&y| *imgbuf.get_pixel(x, y) == Rgba([0, 0, 128, 255]))
+            .count();
+        assert!(pill_rows > 0);
+    }
+
+    #[test]
+    fn generate_card_draws_blurred_shadow() {
+        let mut params = test_params();
+        params.author = None;
+        let plain = generate_card(&test_font(), &params, None);
+        params.shadow = Some(Shadow {
+            offset: point(0.0, 0.0),
+            blur: 4.0,
+            color: (255, 0, 0),
+        });
+        let shadowed = generate_card(&test_font(), &params, None);
+        // ぼかした影が文字の周りに赤くにじむ
+        let reddish = |imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            imgbuf
+                .pixels()
+                .filter(|px| px.0[0] > px.0[1].saturating_add(50))
+                .count()
+        };
+        assert_eq!(reddish(&plain), 0);
+        assert!(reddish(&shadowed) > 0);
+    }
+
+    #[test]
+    fn generate_card_draws_hollow_text() {
+        let render = |fill: &str| {
+            let mut params = parse_params(
+                &test_input(&[("stroke", "4,ff0000"), ("fill", fill)]),
+                Variant::default(),
+            )
+            .unwrap();
+            params.background = (255, 255, 255);
+            params.title_color = (0, 0, 0);
+            params.text_color = (0, 0, 0);
+            params.author_color = (0, 0, 0);
+            generate_card(&test_font(), &params, None)
+        };
+        let count = |imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>, color: [u8; 4]| {
+            imgbuf.pixels().filter(|px| px.0 == color).count()
+        };
+        // 線は赤で引かれ、fill=none のときは文字の中が塗られない
+        let solid = render("solid");
+        assert!(count(&solid, [255, 0, 0, 255]) > 0);
+        assert!(count(&solid, [0, 0, 0, 255]) > 0);
+        let hollow = render("none");
+        assert!(count(&hollow, [255, 0, 0, 255]) > 0);
+        assert_eq!(count(&hollow, [0, 0, 0, 255]), 0);
+    }
+
+    #[test]
+    fn generate_card_without_antialiasing_uses_two_colors() {
+        let mut params = parse_params(&test_input(&[("aa", "false")]), Variant::default()).unwrap();
+        params.background = (255, 255, 255);
+        params.title_color = (0, 0, 0);
+        params.text_color = (0, 0, 0);
+        params.author_color = (0, 0, 0);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        assert!(imgbuf
+            .pixels()
+            .all(|px| px.0 == [255, 255, 255, 255] || px.0 == [0, 0, 0, 255]));
+        assert!(imgbuf.pixels().any(|px| px.0 == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn generate_card_fills_title_with_gradient() {
+        let mut params = parse_params(
+            &test_input(&[("textgradient", "ff0000,0000ff")]),
+            Variant::default(),
+        )
+        .unwrap();
+        params.title = "MMMMMMMM".to_string();
+        params.background = (255, 255, 255);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        // title の行の中で、左端に近い文字は赤く、右端に近い文字は青くなる
+        let solid: Vec<(u32, [u8; 4])> = (0..imgbuf.width())
+            .flat_map(|x| (80..160).map(move |y| (x, y)))
+            .map(|(x, y)| (x, imgbuf.get_pixel(x, y).0))
+            .filter(|(_, px)| px[0].saturating_add(px[2]) >= 250 && px[1] == 0)
+            .collect();
+        let (left, right) = (solid.first().unwrap().1, solid.last().unwrap().1);
+        assert!(left[0] > left[2]);
+        assert!(right[2] > right[0]);
+    }
+
+    #[test]
+    fn generate_card_shifts_text_by_offset() {
+        let mut params = test_params();
+        params.author = None;
+        let plain = generate_card(&test_font(), &params, None);
+        params.offset = point(30.0, -20.0);
+        let shifted = generate_card(&test_font(), &params, None);
+        // 小数の位置の丸めで縁がわずかに変わるだけで、同じ文字が同じ形で動く
+        for y in 60..400 {
+            for x in 0..800 {
+                let (a, b) = (plain.get_pixel(x, y), shifted.get_pixel(x + 30, y - 20));
+                assert!(a.0.iter().zip(b.0).all(|(a, b)| a.abs_diff(b) <= 4));
+            }
+        }
+    }
+
+    #[test]
+    fn generate_card_outlines_bounds_for_debug() {
+        let mut params =
+            parse_params(&test_input(&[("debug", "bounds")]), Variant::default()).unwrap();
+        params.background = (255, 255, 255);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let count = |color: (u8, u8, u8)| {
+            let color = [color.0, color.1, color.2, 255];
+            imgbuf.pixels().filter(|px| px.0 == color).count()
+        };
+        assert!(count(DEBUG_MARGIN_COLOR) > 0);
+        assert!(count(DEBUG_TEXT_COLOR) > 0);
+        // 余白の左端の線は margin_left に引かれる
+        let x = params.margin_left as u32;
+        assert_eq!(
+            (imgbuf.get_pixel(x, 300).0, imgbuf.get_pixel(x - 1, 300).0),
+            ([0, 128, 255, 255], [255, 255, 255, 255])
+        );
+    }
+
+    #[test]
+    fn generate_card_tiles_watermark() {
+        let mut params = test_params();
+        params.background = (255, 255, 255);
+        params.text_color = (0, 0, 0);
+        let plain = generate_card(&test_font(), &params, None);
+        params.watermark = Some("DRAFT".to_string());
+        let watermarked = generate_card(&test_font(), &params, None);
+        // 文字のない下半分の左と右の両方に薄い灰色が入る
+        let faint = |imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>, xs: std::ops::Range<u32>| {
+            xs.flat_map(|x| (400..500).map(move |y| (x, y)))
+                .filter(|&(x, y)| {
+                    let px = imgbuf.get_pixel(x, y).0;
+                    px[0] < 255 && px[0] > 200
+                })
+                .count()
+        };
+        assert_eq!(faint(&plain, 0..300), 0);
+        assert!(faint(&watermarked, 0..300) > 0);
+        assert!(faint(&watermarked, 600..900) > 0);
+    }
+
+    #[test]
+    fn generate_card_draws_category_tab() {
+        let params = parse_params(
+            &test_input(&[("category", "TUTORIAL"), ("categorycolor", "0000ff")]),
+            Variant::default(),
+        )
+        .unwrap();
+        let imgbuf = generate_card(&test_font(), &params, None);
+        // タブは左端から塗られ、文字は白で描かれる
+        assert_eq!(*imgbuf.get_pixel(2, 30), Rgba([0, 0, 255, 255]));
+        assert!((0..params.margin_left as u32 + 100)
+            .any(|x| (24..60).any(|y| *imgbuf.get_pixel(x, y) == Rgba([255, 255, 255, 255]))));
+        assert_eq!(*imgbuf.get_pixel(2, 10), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn generate_svg_draws_text_as_paths() {
+        let mut params = parse_params(
+            &test_input(&[("format", "svg"), ("radius", "24")]),
+            Variant::default(),
+        )
+        .unwrap();
+        params.title = "a < b".to_string();
+        let svg = generate_svg(CardFonts::uniform(&test_font()), &params);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains("a &lt; b"));
+        assert!(svg.contains(r#"clip-path="url(#card)""#));
+        assert!(svg.contains(&format!(r#"fill="{}""#, svg_color(params.background))));
+        assert!(svg.contains("<path d=\"M"));
+    }
+
+    #[test]
+    fn parse_params_rejects_raster_only_options_for_svg() {
+        for extra in [
+            ("bgimage", "https://example.com/a.png"),
+            ("shadow", "2,2,4,000000"),
+            ("print", "1"),
+        ] {
+            assert!(
+                parse_params(&test_input(&[("format", "svg"), extra]), Variant::default()).is_err(),
+                "{extra:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn generate_card_draws_badge() {
+        let mut params = test_params();
+        let without_badge = generate_card(&test_font(), &params, None);
+        params.badge = Some("5 min read".to_string());
+        let with_badge = generate_card(&test_font(), &params, None);
+        assert_ne!(without_badge, with_badge);
+    }
+
+    #[test]
+    fn generate_card_draws_panel_behind_text() {
+        let mut params =
+            parse_params(&test_input(&[("panel", "00000080")]), Variant::default()).unwrap();
+        params.background = (255, 255, 255);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        // 本文の左の余白だけが半透明の黒で塗られる
+        let x = params.margin_left as u32 - PANEL_PADDING as u32 / 2;
+        let panel_rows: Vec<u32> = (0..params.height)
+            .filter(|&y| (120..=135).contains(&imgbuf.get_pixel(x, y).0[0]))
+            .collect();
+        assert!(!panel_rows.is_empty());
+        assert!(panel_rows.len() < 200);
+        assert_eq!(*imgbuf.get_pixel(10, 10), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn generate_card_draws_title_and_text_panels() {
+        let mut params = parse_params(
+            &test_input(&[("titlebg", "ff000080"), ("textbg", "0000ff80")]),
+            Variant::default(),
+        )
+        .unwrap();
+        params.background = (255, 255, 255);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let x = params.margin_left as u32 - PANEL_PADDING as u32 / 2;
+        let rows = |color: [u8; 3]| -> Vec<u32> {
+            (0..params.height)
+                .filter(|&y| {
+                    let px = imgbuf.get_pixel(x, y).0;
+                    (0..3).all(|i| px[i].abs_diff(color[i]) <= 2)
+                })
+                .collect()
+        };
+        // title の帯が上、本文の帯が下に別々に敷かれる
+        let title_rows = rows([255, 127, 127]);
+        let text_rows = rows([127, 127, 255]);
+        assert!(!title_rows.is_empty());
+        assert!(!text_rows.is_empty());
+        assert!(title_rows[0] < text_rows[0]);
+        assert!(title_rows.last() < text_rows.last());
+        assert_eq!(*imgbuf.get_pixel(10, 10), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn generate_card_flips_horizontally() {
+        let mut params = test_params();
+        let original = generate_card(&test_font(), &params, None);
+        params.flip_horizontal = true;
+        let flipped = generate_card(&test_font(), &params, None);
+        assert_eq!(image::imageops::flip_horizontal(&flipped), original);
+    }
+
+    #[test]
+    fn generate_card_draws_over_template() {
+        let template =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(100, 50, Rgba([0, 0, 255, 255])));
+        let mut params = test_params();
+        params.blur = 5.0;
+        let imgbuf = generate_card(&test_font(), &params, Some(&template));
+        assert_eq!(imgbuf.dimensions(), (IMAGE_WIDTH, IMAGE_HEIGHT));
+        assert_eq!(*imgbuf.get_pixel(600, 620), Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn parse_params_centers_text_width() {
+        let params =
+            parse_params(&test_input(&[("textwidth", "80%")]), Variant::default()).unwrap();
+        assert_eq!(params.margin_left, 120.0);
+        assert_eq!(params.margin_right, 120.0);
+        for text_width in ["5%", "120%", "abc"] {
+            let input = test_input(&[("textwidth", text_width)]);
+            assert!(parse_params(&input, Variant::default()).is_err());
+        }
+        let input = test_input(&[("textwidth", "80%"), ("marginleft", "10")]);
+        assert!(parse_params(&input, Variant::default()).is_err());
+    }
+
+    #[test]
+    fn parse_params_limits_blur() {
+        let max = MAX_BLUR.to_string();
+        let params = parse_params(&test_input(&[("blur", &max)]), Variant::default()).unwrap();
+        assert_eq!(params.blur, MAX_BLUR);
+        assert_eq!(
+            parse_params(&test_input(&[("blur", "1000")]), Variant::default()).err(),
+            Some(format!("blur must be between 0 and {MAX_BLUR}"))
+        );
+    }
+
+    #[test]
+    fn blend_pixel_linear_brightens_half_coverage() {
+        let mut srgb = Rgba([255, 255, 255, 255]);
+        blend_pixel(&mut srgb, (0, 0, 0), 0.5);
+        let mut linear = Rgba([255, 255, 255, 255]);
+        blend_pixel_linear(&mut linear, (0, 0, 0), 0.5);
+        assert_eq!(srgb, Rgba([127, 127, 127, 255]));
+        assert_eq!(linear, Rgba([188, 188, 188, 255]));
+
+        for (v, expected) in [(0.0, 255), (1.0, 0)] {
+            let mut px = Rgba([255, 255, 255, 255]);
+            blend_pixel_linear(&mut px, (0, 0, 0), v);
+            assert_eq!(px.0[0], expected);
+        }
+    }
+
+    #[test]
+    fn apply_filter_grayscale_desaturates() {
+        let mut imgbuf = ImageBuffer::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        apply_filter(&mut imgbuf, Filter::Grayscale);
+        assert!(imgbuf.pixels().all(|px| *px == Rgba([76, 76, 76, 255])));
+    }
+
+    #[test]
+    fn negotiate_error_format_prefers_text_on_ties() {
+        // <img> からの取得
+        assert_eq!(
+            negotiate_error_format("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"),
+            ErrorFormat::Image
+        );
+        // ブラウザで直接開いたとき
+        assert_eq!(
+            negotiate_error_format(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8"
+            ),
+            ErrorFormat::Text
+        );
+        assert_eq!(
+            negotiate_error_format("application/json, text/plain;q=0.5"),
+            ErrorFormat::Json
+        );
+        assert_eq!(negotiate_error_format("image/png;q=0"), ErrorFormat::Text);
+        assert_eq!(negotiate_error_format("*/*"), ErrorFormat::Text);
+        assert_eq!(negotiate_error_format(""), ErrorFormat::Text);
+    }
+
+    #[test]
+    fn negotiate_format_reads_accept_header() {
+        assert_eq!(
+            negotiate_format("image/avif,image/webp,image/apng,*/*;q=0.8"),
+            OutputFormat::WebP
+        );
+        assert_eq!(negotiate_format("image/webp;q=0, */*"), OutputFormat::Png);
+        assert_eq!(negotiate_format(""), OutputFormat::Png);
+    }
+
+    #[test]
+    fn parse_params_accepts_only_444_subsampling() {
+        let subsampling = |value: &str| {
+            parse_params(
+                &test_input(&[("format", "jpeg"), ("subsampling", value)]),
+                Variant::default(),
+            )
+            .map(|params| params.subsampling)
+        };
+        assert_eq!(subsampling("444"), Ok(Subsampling::Yuv444));
+        assert_eq!(
+            subsampling("420"),
+            Err("subsampling parameter is invalid".to_string())
+        );
+    }
+
+    #[test]
+    fn error_cache_key_includes_format() {
+        let url = "https://example.com/?title=a";
+        assert_eq!(error_cache_key(url, ErrorFormat::Text), url);
+        assert_eq!(
+            error_cache_key(url, ErrorFormat::Json),
+            "https://example.com/?title=a&errorformat=json"
+        );
+        assert_eq!(
+            error_cache_key("https://example.com/", ErrorFormat::Image),
+            "https://example.com/?errorformat=png"
+        );
+    }
+
+    #[test]
+    fn error_card_title_follows_status() {
+        assert_eq!(error_card_title(400), "Invalid request");
+        assert_eq!(error_card_title(404), "Not found");
+        assert_eq!(error_card_title(413), "Request too large");
+        assert_eq!(error_card_title(500), "Something went wrong");
+    }
+
+    #[test]
+    fn encode_image_writes_png_signature() {
+        let params = test_params();
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let png = encode_image(&imgbuf, OutputFormat::Png, &params).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn parse_params_scales_for_print() {
+        let params = parse_params(&test_input(&[("print", "1")]), Variant::default()).unwrap();
+        assert_eq!(params.scale, 3.0);
+        assert_eq!(params.canvas_size(), (3600, 1890));
+        assert_eq!(params.dpi, Some(PRINT_DPI));
+        let params = parse_params(
+            &test_input(&[("print", "1"), ("dpi", "600")]),
+            Variant::default(),
+        )
+        .unwrap();
+        assert_eq!(params.dpi, Some(600));
+
+        for extra in [[("format", "jpeg")], [("height", "2400")]] {
+            let input = test_input(&[&[("print", "1")], &extra[..]].concat());
+            assert!(parse_params(&input, Variant::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn generate_card_scales_whole_layout() {
+        let ink_bounds = |imgbuf: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            let ink: Vec<(u32, u32)> = imgbuf
+                .enumerate_pixels()
+                .filter(|(_, _, px)| px.0[0] < 128)
+                .map(|(x, y, _)| (x, y))
+                .collect();
+            (
+                ink.iter().map(|p| p.0).min().unwrap() as f32,
+                ink.iter().map(|p| p.1).min().unwrap() as f32,
+                ink.iter().map(|p| p.0).max().unwrap() as f32,
+                ink.iter().map(|p| p.1).max().unwrap() as f32,
+            )
+        };
+        let mut params = test_params();
+        let original = generate_card(&test_font(), &params, None);
+        params.scale = 2.0;
+        let scaled = generate_card(&test_font(), &params, None);
+        assert_eq!(scaled.dimensions(), (IMAGE_WIDTH * 2, IMAGE_HEIGHT * 2));
+
+        let (x0, y0, x1, y1) = ink_bounds(&original);
+        let (sx0, sy0, sx1, sy1) = ink_bounds(&scaled);
+        for (a, b) in [(x0, sx0), (y0, sy0), (x1, sx1), (y1, sy1)] {
+            assert!((a * 2.0 - b).abs() <= 3.0, "{a} {b}");
+        }
+    }
+
+    // ETag やキャッシュのために同じパラメータからは同じバイト列を返す
+    #[test]
+    fn encode_png_is_deterministic() {
+        let input = test_input(&[
+            ("shadow", "2,2,4,000000"),
+            ("stroke", "2"),
+            ("textgradient", "ff0000,0000ff"),
+            ("watermark", "draft"),
+            ("embedmeta", "1"),
+        ]);
+        let render = || {
+            let params = parse_params(&input, Variant::default()).unwrap();
+            let imgbuf = generate_card(&test_font(), &params, None);
+            encode_image(&imgbuf, OutputFormat::Png, &params).unwrap()
+        };
+        assert_eq!(render(), render());
+    }
+
+    #[test]
+    fn encode_png_writes_physical_dimensions() {
+        let mut params = test_params();
+        params.dpi = Some(144);
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let png = encode_image(&imgbuf, OutputFormat::Png, &params).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let dims = reader.info().pixel_dims.unwrap();
+        assert_eq!(dims.xppu, 5669);
+        assert_eq!(dims.yppu, 5669);
+        assert!(matches!(dims.unit, png::Unit::Meter));
+    }
+
+    #[test]
+    fn encode_png_embeds_metadata_only_when_requested() {
+        let mut params = test_params();
+        params.title = "タイトル".to_string();
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let itxt = |params: &Params| {
+            let png = encode_image(&imgbuf, OutputFormat::Png, params).unwrap();
+            let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+            reader
+                .info()
+                .utf8_text
+                .iter()
+                .map(|chunk| (chunk.keyword.clone(), chunk.get_text().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert!(itxt(&params).is_empty());
+        params.embed_meta = true;
+        assert_eq!(
+            itxt(&params),
+            vec![
+                ("Title".to_string(), "タイトル".to_string()),
+                ("Description".to_string(), "text".to_string()),
+                ("Author".to_string(), "author".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_png_strips_alpha_when_requested() {
+        let mut params = test_params();
+        params.radius = 50.0;
+        params.strip_alpha = true;
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let buffer = encode_image(&imgbuf, OutputFormat::Png, &params).unwrap();
+        let decoded = image::load_from_memory(&buffer).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        assert_eq!(decoded.to_rgb8().get_pixel(0, 0), &Rgb([255, 255, 255]));
+    }
 
-        target.push(glyph);
+    #[test]
+    fn encode_image_writes_jpeg_and_webp() {
+        let params = test_params();
+        let imgbuf = generate_card(&test_font(), &params, None);
+        let jpeg = encode_image(&imgbuf, OutputFormat::Jpeg, &params).unwrap();
+        assert_eq!(&jpeg[..2], b"\xff\xd8");
+        let webp = encode_image(&imgbuf, OutputFormat::WebP, &params).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
     }
 }
@@ -0,0 +1,137 @@
+// /batch で返す ZIP (無圧縮) を組み立てる (APPNOTE 6.3.10)
+// PNG はすでに圧縮されているので deflate せずに格納する
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+// 出力が入力だけで決まるように更新日時は 1980-01-01 00:00 に固定する
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+// ファイル名を UTF-8 として扱うフラグ
+const FLAG_UTF8: u16 = 1 << 11;
+
+// 64 KiB を超えるエントリ数や 4 GiB を超えるサイズ (ZIP64) は扱わない
+// 収まらない場合は壊れた ZIP を書かずにエラーにする
+pub(crate) fn write_zip(entries: &[(String, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    let count = u16::try_from(entries.len()).map_err(|_| "too many zip entries".to_string())?;
+    let mut buffer = Vec::new();
+    let mut central_directory = Vec::new();
+    for (name, data) in entries {
+        let offset = to_u32(buffer.len(), "zip archive")?;
+        let crc = crc32fast::hash(data);
+        let size = to_u32(data.len(), name)?;
+
+        buffer.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+        write_entry_fields(&mut buffer, name, crc, size)?;
+        buffer.extend_from_slice(name.as_bytes());
+        buffer.extend_from_slice(data);
+
+        central_directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+        // version made by
+        central_directory.extend_from_slice(&20u16.to_le_bytes());
+        write_entry_fields(&mut central_directory, name, crc, size)?;
+        // comment length, disk number, internal attributes, external attributes
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&0u32.to_le_bytes());
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+
+    let central_directory_offset = to_u32(buffer.len(), "zip archive")?;
+    let central_directory_size = to_u32(central_directory.len(), "zip central directory")?;
+    buffer.extend_from_slice(&central_directory);
+    buffer.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    // disk number, disk with central directory
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    buffer.extend_from_slice(&count.to_le_bytes());
+    buffer.extend_from_slice(&count.to_le_bytes());
+    buffer.extend_from_slice(&central_directory_size.to_le_bytes());
+    buffer.extend_from_slice(&central_directory_offset.to_le_bytes());
+    // comment length
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    Ok(buffer)
+}
+
+fn to_u32(len: usize, what: &str) -> Result<u32, String> {
+    u32::try_from(len).map_err(|_| format!("{what} is too large for zip"))
+}
+
+// local file header と central directory header で共通の部分
+fn write_entry_fields(buffer: &mut Vec<u8>, name: &str, crc: u32, size: u32) -> Result<(), String> {
+    let name_len =
+        u16::try_from(name.len()).map_err(|_| format!("zip entry name {name} is too long"))?;
+    // version needed to extract
+    buffer.extend_from_slice(&20u16.to_le_bytes());
+    buffer.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+    // compression method (stored)
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    buffer.extend_from_slice(&DOS_TIME.to_le_bytes());
+    buffer.extend_from_slice(&DOS_DATE.to_le_bytes());
+    buffer.extend_from_slice(&crc.to_le_bytes());
+    // compressed size, uncompressed size
+    buffer.extend_from_slice(&size.to_le_bytes());
+    buffer.extend_from_slice(&size.to_le_bytes());
+    buffer.extend_from_slice(&name_len.to_le_bytes());
+    // extra field length
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+    }
+
+    fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn write_zip_stores_crc32() {
+        let buffer = write_zip(&[("a.txt".to_string(), b"123456789".to_vec())]).unwrap();
+        assert_eq!(u32_at(&buffer, 14), 0xcbf43926);
+    }
+
+    #[test]
+    fn write_zip_stores_entries() {
+        let entries = vec![
+            ("a.png".to_string(), b"hello".to_vec()),
+            ("b.png".to_string(), b"world!".to_vec()),
+        ];
+        let buffer = write_zip(&entries).unwrap();
+
+        assert_eq!(u32_at(&buffer, 0), LOCAL_FILE_HEADER);
+        assert_eq!(u32_at(&buffer, 14), 0x3610a686);
+        assert_eq!(&buffer[30..35], b"a.png");
+        assert_eq!(&buffer[35..40], b"hello");
+        assert_eq!(u32_at(&buffer, 40), LOCAL_FILE_HEADER);
+
+        // 末尾の end of central directory からエントリをたどれる
+        let end = buffer.len() - 22;
+        assert_eq!(u32_at(&buffer, end), END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u16_at(&buffer, end + 10), 2);
+        let central_directory = u32_at(&buffer, end + 16) as usize;
+        assert_eq!(u32_at(&buffer, central_directory), CENTRAL_DIRECTORY_HEADER);
+        assert_eq!(u32_at(&buffer, central_directory + 42), 0);
+        let second = central_directory + 46 + 5;
+        assert_eq!(u32_at(&buffer, second), CENTRAL_DIRECTORY_HEADER);
+        assert_eq!(u32_at(&buffer, second + 42), 40);
+        assert_eq!(&buffer[second + 46..second + 51], b"b.png");
+    }
+
+    #[test]
+    fn write_zip_rejects_too_many_entries() {
+        let entries = vec![("a".to_string(), Vec::new()); u16::MAX as usize + 1];
+        assert_eq!(write_zip(&entries), Err("too many zip entries".to_string()));
+        let long_name = "a".repeat(u16::MAX as usize + 1);
+        assert!(write_zip(&[(long_name, Vec::new())]).is_err());
+    }
+}